@@ -23,16 +23,57 @@
 @group(0) @binding(0)
 var<uniform> view: View;
 
-struct CubeArray {
-  arr: array<Cube>,
-}
-
 struct Cube {
   transform: mat4x4f,
 }
 
+// See `PackedFace` in `src/render/buffers.rs` for the bit layout.
+struct PackedFace {
+  position: u32,
+  block: u32,
+}
+
+#ifdef CHUNK_BINDING_ARRAY
+struct FaceArray {
+  arr: array<PackedFace>,
+}
+#endif
+
 @group(1) @binding(0)
-var<storage, read> chunks: binding_array<CubeArray>;
+var<storage, read> cubes: array<Cube>;
+
+@group(1) @binding(1)
+var<storage, read> materials: array<vec4f>;
+
+#ifdef CHUNK_BINDING_ARRAY
+// One face buffer and transform per chunk slot.
+@group(2) @binding(0)
+var<storage, read> chunks: binding_array<FaceArray>;
+#else
+// The faces of every chunk slot back to back. Each chunk draw starts at
+// its slot's offset, so the vertex index addresses the face directly.
+@group(2) @binding(0)
+var<storage, read> chunk_faces: array<PackedFace>;
+#endif
+
+@group(2) @binding(1)
+var<storage, read> chunk_transforms: array<mat4x4f>;
+
+@group(2) @binding(2)
+var<storage, read> previous_chunk_transforms: array<mat4x4f>;
+
+#ifdef MOTION_VECTOR_PREPASS
+// Mirrors `bevy_pbr::prepass_bindings`, which is not imported as a whole
+// because its bindings would clash with the main pass view bindings.
+struct PreviousViewUniforms {
+    view_from_world: mat4x4f,
+    clip_from_world: mat4x4f,
+    clip_from_view: mat4x4f,
+}
+
+@group(0) @binding(2)
+var<uniform> previous_view_uniforms: PreviousViewUniforms;
+#endif
 
 // Information passed from the vertex shader to the fragment shader.
 struct VertexOutput {
@@ -42,6 +83,8 @@ struct VertexOutput {
     @location(0) color: vec3<f32>,
     @location(1) normal: vec3<f32>,
     @location(2) world_position: vec4f,
+    // Where the vertex was last frame, for motion vectors.
+    @location(3) previous_world_position: vec4f,
 };
 
 const VERTICES = array<vec3f, 36>(
@@ -110,64 +153,136 @@ const NORMALS = array<vec3f, 6> (
   vec3f( 0.0, 0.0,-1.0),
 );
 
-const TEST_AXIS = array<vec4f, 3> (
-    vec4f(1.0, 0.0, 0.0, 1.0),
-    vec4f(0.0, 1.0, 0.0, 1.0),
-    vec4f(0.0, 0.0, 1.0, 1.0),
+// The vertex counts are shader defs set by the pipeline, so they always
+// match the draws issued on the CPU side.
+const VERTEX_COUNT: u32 = #{VERTICES_PER_CUBE};
+
+// Draws with an instance index of at least this pull packed faces from
+// chunk slot `instance_index - FIRST_CHUNK_INSTANCE` instead of cubes.
+const FIRST_CHUNK_INSTANCE: u32 = #{FIRST_CHUNK_INSTANCE};
+
+const VERTICES_PER_FACE: u32 = #{VERTICES_PER_FACE};
+
+// Quad corners as (u, v) offsets, matching `Quad::corners`.
+const FACE_CORNERS = array<vec2f, 4>(
+  vec2f(0.0, 0.0),
+  vec2f(1.0, 0.0),
+  vec2f(1.0, 1.0),
+  vec2f(0.0, 1.0),
 );
 
-const VERTEX_COUNT: u32 = 18;
+// Counter-clockwise when seen from the face normal, for positive and
+// negative directions respectively.
+const FACE_TRIANGLES_POSITIVE = array<u32, 6>(0, 1, 2, 0, 2, 3);
+const FACE_TRIANGLES_NEGATIVE = array<u32, 6>(0, 2, 1, 0, 3, 2);
+
+const AO_STRENGTH: f32 = 0.2;
 
-fn get_base_vertex(index: u32) -> vec4f {
-  return vec4f(VERTICES[index % VERTEX_COUNT], 1.0);
+// `VERTICES` holds the positive face of each axis followed by the negative
+// one, six vertices each.
+fn get_base_vertex(axis: u32, positive: bool, corner: u32) -> vec4f {
+  return vec4f(VERTICES[axis * 12u + select(6u, 0u, positive) + corner], 1.0);
 }
 
-fn get_base_normal(index: u32) -> vec3f {
-  return NORMALS[(index / 6) % 6];
+fn get_base_normal(axis: u32, positive: bool) -> vec3f {
+  return NORMALS[axis * 2u + select(1u, 0u, positive)];
 }
 
-// The vertex shader entry point.
-@vertex
-fn vertex(@builtin(vertex_index) index: u32) -> VertexOutput {
-    // Use an orthographic projection.
+fn face_vertex(index: u32, chunk_slot: u32) -> VertexOutput {
     var vertex_output: VertexOutput;
 
-    let chunk_num = index % 2;
-
-    var instance = index / VERTEX_COUNT;
-    instance += 0;
-    let axis = (index - instance) / 6;
-    var cube_pos = (vec4(0, 0, 0, 1) * chunks[chunk_num].arr[instance].transform)[axis];
-    var axis_test = (TEST_AXIS[axis] * chunks[chunk_num].arr[instance].transform)[axis];
-    let sign = sign(cube_pos);
-    axis_test *= sign;
-    cube_pos *= sign;
-    var vertex = axis * 12 + (index % 6);
-    if (f32(axis) > cube_pos) {
-        vertex = vertex + 6;
+#ifdef CHUNK_BINDING_ARRAY
+    let face = chunks[chunk_slot].arr[index / VERTICES_PER_FACE];
+#else
+    let face = chunk_faces[index / VERTICES_PER_FACE];
+#endif
+
+    let block_pos = vec3f(
+        f32(face.position & 31u),
+        f32((face.position >> 5u) & 31u),
+        f32((face.position >> 10u) & 31u),
+    );
+    let direction = (face.position >> 15u) & 7u;
+    let width = f32(((face.position >> 18u) & 31u) + 1u);
+    let height = f32(((face.position >> 23u) & 31u) + 1u);
+    let block = face.block & 0xffffu;
+    let ao = (face.block >> 16u) & 0xffu;
+
+    let axis = direction / 2u;
+    let u_axis = (axis + 1u) % 3u;
+    let v_axis = (axis + 2u) % 3u;
+    let positive = direction % 2u == 0u;
+
+    var corner_index: u32;
+    if (positive) {
+        corner_index = FACE_TRIANGLES_POSITIVE[index % VERTICES_PER_FACE];
+    } else {
+        corner_index = FACE_TRIANGLES_NEGATIVE[index % VERTICES_PER_FACE];
     }
+    let corner = FACE_CORNERS[corner_index];
 
-    var vertex_pos: vec4f = get_base_vertex(vertex);
-    vertex_pos = vertex_pos *
-                 chunks[chunk_num].arr[index / VERTEX_COUNT].transform;
-    vertex_output.world_position = vertex_pos;
-    vertex_pos = view.clip_from_world * vertex_pos;
-    vertex_output.clip_position = vertex_pos;
-    let i = index % 3;
-    if (i == 0) {
-      vertex_output.color = vec3f(1.0, 0.0, 0.0);
-    } else if (i == 1) {
-      vertex_output.color = vec3f(0.0, 1.0, 0.0);
-    } if (i == 2) {
-      vertex_output.color = vec3f(0.0, 0.0, 1.0);
+    // Blocks are centered on integer positions.
+    var local_pos = block_pos - vec3f(0.5);
+    if (positive) {
+        local_pos[axis] += 1.0;
     }
-    vertex_output.normal = get_base_normal(index);
+    local_pos[u_axis] += corner.x * width;
+    local_pos[v_axis] += corner.y * height;
+
+    let transform = chunk_transforms[chunk_slot];
+    let world_position = transform * vec4f(local_pos, 1.0);
+
+    vertex_output.world_position = world_position;
+    vertex_output.previous_world_position =
+        previous_chunk_transforms[chunk_slot] * vec4f(local_pos, 1.0);
+    vertex_output.clip_position = view.clip_from_world * world_position;
+    vertex_output.normal = normalize((transform * vec4f(NORMALS[direction], 0.0)).xyz);
 
+    let occlusion = f32((ao >> (corner_index * 2u)) & 3u);
+    vertex_output.color = materials[block].rgb * (1.0 - AO_STRENGTH * occlusion);
+
+    return vertex_output;
+}
+
+// The vertex shader entry point.
+// Shared by the main, prepass and shadow passes.
+fn pulled_vertex(index: u32, instance_index: u32) -> VertexOutput {
+    if (instance_index >= FIRST_CHUNK_INSTANCE) {
+        return face_vertex(index, instance_index - FIRST_CHUNK_INSTANCE);
+    }
+
+    var vertex_output: VertexOutput;
+
+    // Only the three faces of a cube that point towards the camera are
+    // drawn, one per axis.
+    let transform = cubes[index / VERTEX_COUNT].transform;
+    let local = index % VERTEX_COUNT;
+    let axis = local / 6u;
+    let corner = local % 6u;
+
+    let center = (vec4f(0.0, 0.0, 0.0, 1.0) * transform).xyz;
+    let axis_direction = (vec4f(NORMALS[axis * 2u], 0.0) * transform).xyz;
+    let positive = dot(view.world_position - center, axis_direction) > 0.0;
+
+    let world_position = get_base_vertex(axis, positive, corner) * transform;
+    vertex_output.world_position = world_position;
+    // Cubes are re-uploaded every frame without history.
+    vertex_output.previous_world_position = world_position;
+    vertex_output.clip_position = view.clip_from_world * world_position;
+    vertex_output.normal = normalize((vec4f(get_base_normal(axis, positive), 0.0) * transform).xyz);
     vertex_output.color = vec3(0.5, 0.5, 1.0);
 
     return vertex_output;
 }
 
+@vertex
+fn vertex(
+    @builtin(vertex_index) index: u32,
+    @builtin(instance_index) instance_index: u32,
+) -> VertexOutput {
+    return pulled_vertex(index, instance_index);
+}
+
 // The fragment shader entry point.
 @fragment
 fn fragment(vertex: VertexOutput) -> @location(0) vec4f {
@@ -200,15 +315,89 @@ fn fragment(vertex: VertexOutput) -> @location(0) vec4f {
     return main_pass_post_lighting_processing(pbr_input, out.color);
 }
 
-// @vertex
-// fn shadow_vertex(@builtin(vertex_index) index: u32) -> @builtin(position) vec4f {
-//     var vertex_pos = get_base_vertex(index);
-//     vertex_pos *= cuboids[index / VERTEX_COUNT];
-//     vertex_pos = view.clip_from_world * vertex_pos;
-//     return vertex_pos;
-// }
-// 
-// @fragment
-// fn shadow_fragment(@builtin(position) clip_pos: vec4f) {
-//     return;
-// }
+struct PrepassVertexOutput {
+    @builtin(position) position: vec4f,
+#ifdef NORMAL_PREPASS
+    @location(0) world_normal: vec3f,
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    @location(1) world_position: vec4f,
+    @location(2) previous_world_position: vec4f,
+#endif
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    @location(3) unclipped_depth: f32,
+#endif
+}
+
+#ifdef PREPASS_FRAGMENT
+struct PrepassFragmentOutput {
+#ifdef NORMAL_PREPASS
+    @location(0) normal: vec4f,
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    @location(1) motion_vector: vec2f,
+#endif
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    @builtin(frag_depth) frag_depth: f32,
+#endif
+}
+#endif
+
+// Entry point for camera prepasses and for directional, point and spot
+// light shadow maps. `view` is the light's view in the latter case.
+@vertex
+fn prepass_vertex(
+    @builtin(vertex_index) index: u32,
+    @builtin(instance_index) instance_index: u32,
+) -> PrepassVertexOutput {
+    let vertex = pulled_vertex(index, instance_index);
+
+    var out: PrepassVertexOutput;
+    out.position = vertex.clip_position;
+
+#ifdef NORMAL_PREPASS
+    out.world_normal = vertex.normal;
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    out.world_position = vertex.world_position;
+    out.previous_world_position = vertex.previous_world_position;
+#endif
+
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    // Keep casters in front of a cascade's near plane instead of clipping
+    // them, the fragment shader writes their real depth.
+    out.unclipped_depth = out.position.z;
+    out.position.z = min(out.position.z, 1.0);
+#endif
+
+    return out;
+}
+
+#ifdef PREPASS_FRAGMENT
+// Only used when the pass has color targets or depth clamping is emulated.
+@fragment
+fn prepass_fragment(in: PrepassVertexOutput) -> PrepassFragmentOutput {
+    var out: PrepassFragmentOutput;
+
+#ifdef NORMAL_PREPASS
+    out.normal = vec4f(normalize(in.world_normal) * 0.5 + vec3f(0.5), 1.0);
+#endif
+
+#ifdef MOTION_VECTOR_PREPASS
+    let clip_position_t = view.unjittered_clip_from_world * in.world_position;
+    let clip_position = clip_position_t.xy / clip_position_t.w;
+    let previous_clip_position_t =
+        previous_view_uniforms.clip_from_world * in.previous_world_position;
+    let previous_clip_position = previous_clip_position_t.xy / previous_clip_position_t.w;
+    // Motion vectors are offsets in UV space, where y points down.
+    out.motion_vector = (clip_position - previous_clip_position) * vec2f(0.5, -0.5);
+#endif
+
+#ifdef UNCLIPPED_DEPTH_ORTHO_EMULATION
+    out.frag_depth = in.unclipped_depth;
+#endif
+
+    return out;
+}
+#endif
@@ -1,64 +1,38 @@
 mod shared;
 
-use bevy::{math::{I64Vec3, Vec3A}, pbr::CascadeShadowConfigBuilder, prelude::*, render::primitives::Aabb};
-use shared::SharedUtilitiesPlugin;
-use vkxl::{world::{generation, Level, Load}, VoxelPlugin};
+use bevy::{pbr::CascadeShadowConfigBuilder, prelude::*};
+use shared::{spawn_player, Player, SharedUtilitiesPlugin};
+use vkxl::{
+    render::VoxelRendererPlugin,
+    world::{generation, streaming::ChunkLoader, Level},
+    VoxelPlugin,
+};
 
 fn main() {
     let mut app = App::new();
-    app.add_plugins((DefaultPlugins, VoxelPlugin, SharedUtilitiesPlugin))
-        .add_systems(Startup, setup);
-    // Make sure to tell Bevy to check our entity for visibility. Bevy won't
-    // do this by default, for efficiency reasons.
-
-    // We make sure to add these to the render app, not the main app.
+    app.add_plugins((
+        DefaultPlugins,
+        VoxelPlugin::default(),
+        VoxelRendererPlugin::default(),
+        SharedUtilitiesPlugin,
+    ))
+        .add_systems(Startup, (setup, stream_around_player.after(spawn_player)));
 
     app.run();
 }
 
+/// Loads the level around the player as it moves.
+fn stream_around_player(mut commands: Commands, player: Single<Entity, With<Player>>) {
+    commands.entity(*player).insert(ChunkLoader::default());
+}
+
 /// Spawns the objects in the scene.
 fn setup(mut commands: Commands) {
-    let world = Level {
-        generator: generation::debug::sine::<8, 3, 10>,
-    };
-
-    let id = commands.spawn((
+    commands.spawn((
         Visibility::default(),
         Transform::from_translation(Vec3::new(-1.5, 0.1, 0.0)),
-        // This `Aabb` is necessary for the visibility checks to work.
-        Aabb {
-            center: Vec3A::ZERO,
-            half_extents: Vec3A::splat(10.0),
-        },
-        world,
-    )).id();
-
-    // let chunk = Chunk16::generate(generation::debug::sine::<5, 3, 10>, I64Vec3::ZERO);
-
-    //     commands.spawn((
-    //         Visibility::default(),
-    //         Transform::from_translation(Vec3::new(-1.5, 0.1, 0.0)),
-    //         // This `Aabb` is necessary for the visibility checks to work.
-    //         Aabb {
-    //             center: Vec3A::ZERO,
-    //             half_extents: Vec3A::splat(10.0),
-    //         },
-    //         chunk,
-    //     ));
-
-    let world2 = Level {
-        generator: generation::debug::sine::<8, 3, 10>,
-    };
-
-    let mut level = (id, &world2);
-
-    for x in 0..16 {
-        for z in 0..16 {
-            level.load::<16>(I64Vec3::new(x, 0, z), &mut commands);
-        }
-    }
-
-
+        Level::new(generation::debug::sine::<8, 3, 10>),
+    ));
 
     commands.spawn((
         DirectionalLight {
@@ -1,13 +1,64 @@
-use bevy::app::Plugin;
-use render::VoxelRendererPlugin;
+use bevy::app::{Plugin, Update};
+use render::mesher::MeshingMode;
+use world::{
+    block::BlockRegistry,
+    chunk::{ChunkSize, ChunkSizes},
+    prune_feature_writes,
+};
 
 pub mod render;
 pub mod world;
 
-pub struct VoxelPlugin;
+/// Simulates levels and their chunks. Does not need a renderer, so it runs
+/// under `MinimalPlugins` on servers and in tests; add
+/// [`VoxelRendererPlugin`](render::VoxelRendererPlugin) to draw them.
+pub struct VoxelPlugin {
+    /// Sizes of the chunks to simulate, 16 by default. Several sizes can be
+    /// simulated side by side.
+    pub chunk_sizes: Vec<ChunkSize>,
+}
+
+impl Default for VoxelPlugin {
+    fn default() -> Self {
+        Self::with_chunk_size::<16>()
+    }
+}
+
+impl VoxelPlugin {
+    /// Simulates chunks of `SIZE` only.
+    pub fn with_chunk_size<const SIZE: usize>() -> Self {
+        Self {
+            chunk_sizes: vec![ChunkSize::new::<SIZE>()],
+        }
+    }
+
+    /// Simulates chunks of `SIZE` too.
+    pub fn and_chunk_size<const SIZE: usize>(mut self) -> Self {
+        self.chunk_sizes.push(ChunkSize::new::<SIZE>());
+        self
+    }
+
+    /// Meshes the chunks of every size with `meshing` once rendered.
+    pub fn with_meshing(mut self, meshing: MeshingMode) -> Self {
+        for chunk_size in &mut self.chunk_sizes {
+            chunk_size.meshing = meshing;
+        }
+        self
+    }
+}
 
 impl Plugin for VoxelPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app.add_plugins(VoxelRendererPlugin);
+        let mut chunk_sizes = self.chunk_sizes.clone();
+        chunk_sizes.sort_unstable_by_key(ChunkSize::size);
+        chunk_sizes.dedup_by_key(|chunk_size| chunk_size.size());
+
+        for chunk_size in &chunk_sizes {
+            chunk_size.simulate(app);
+        }
+
+        app.init_resource::<BlockRegistry>()
+            .insert_resource(ChunkSizes(chunk_sizes))
+            .add_systems(Update, prune_feature_writes);
     }
 }
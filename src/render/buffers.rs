@@ -1,19 +1,30 @@
-use std::{num::NonZero, usize};
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZero,
+    ops::Range,
+};
 
 use bevy::{
-    math::{Mat4, Vec3}, prelude::{
-        Commands, FromWorld, Query, Res, ResMut, Resource, Transform, ViewVisibility, World,
-    }, render::{
-        render_resource::{BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntry, BindingResource, BindingType, BufferBindingType, BufferUsages, BufferVec, ShaderStages, ShaderType},
+    color::ColorToComponents, log::warn_once, math::{I64Vec3, IVec3, Mat4, Vec4}, prelude::{
+        Commands, DetectChanges, Entity, FromWorld, LinearRgba, Local, Query, Ref, RemovedComponents, Res, ResMut, Resource, GlobalTransform, Transform, ViewVisibility, World,
+    }, pbr::LightEntity, render::{
+        primitives::Frustum,
+        render_resource::{BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntry, BindingResource, BindingType, BufferBindingType, BufferUsages, BufferVec, RawBufferVec, ShaderStages, ShaderType},
         renderer::{RenderDevice, RenderQueue},
+        settings::WgpuFeatures,
     }
 };
 use bytemuck::{Pod, Zeroable};
 
-use crate::world::chunk::ExtractedChunk;
+use crate::world::{
+    block::{BlockMaterial, BlockRegistry, BlockState},
+    chunk::{Chunk, ChunkMeshing, ExtractedChunk},
+};
 
 use super::{
-    pipeline::{create_bind_group, CubePullingPipeline},
+    diagnostics::ChunkRemeshCounter,
+    mesher::{ChunkNeighbours, FaceDirection, Quad},
+    pipeline::{create_bind_group, CubePullingPipeline, FIRST_CHUNK_INSTANCE, VERTICES_PER_FACE},
     PulledCube,
 };
 
@@ -21,80 +32,324 @@ pub const MAX_CHUNK_COUNT : u32 = 256;
 
 #[derive(Resource)]
 pub struct PulledCubesBuffers {
+    /// Free-standing [`PulledCube`] entities.
     pub(crate) instances: BufferVec<Cube>,
+    /// Base color of each registered block, indexed by block id.
+    pub(crate) materials: BufferVec<Vec4>,
+    /// Set when every chunk has to be re-meshed, e.g. after the block
+    /// registry changed. Cleared once the buffers are written.
     pub(crate) dirty: bool,
 }
 
+/// How chunk faces are bound for the shader, picked from the render
+/// device's features when the renderer plugin finishes building.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStorage {
+    /// One face buffer per chunk slot, bound as a `binding_array`.
+    BindingArray,
+    /// The faces of every slot concatenated into a single buffer, each
+    /// chunk draw starting at its slot's offset. Every change re-uploads
+    /// all faces, but it works on any device, including WebGPU.
+    Concatenated,
+}
+
+impl ChunkStorage {
+    /// Storage buffers bound next to the chunk face array, which count
+    /// towards the same per-stage limit. Leaves room for the view bindings.
+    const OTHER_STORAGE_BUFFERS: u32 = 16;
+
+    pub fn for_device(render_device: &RenderDevice) -> Self {
+        let required = WgpuFeatures::BUFFER_BINDING_ARRAY
+            | WgpuFeatures::STORAGE_RESOURCE_BINDING_ARRAY
+            | WgpuFeatures::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING;
+
+        if render_device.features().contains(required)
+            && render_device.limits().max_storage_buffers_per_shader_stage
+                >= MAX_CHUNK_COUNT + Self::OTHER_STORAGE_BUFFERS
+        {
+            Self::BindingArray
+        } else {
+            Self::Concatenated
+        }
+    }
+}
+
+/// Per-chunk face buffers, one slot per extracted chunk entity, bound
+/// according to [`ChunkStorage`].
 #[derive(Resource)]
 pub struct PulledCubesBufferArrays {
-    pub chunk_instances: Vec<BufferVec<Cube>>,
+    /// Packed faces of the chunk in each slot, always [`MAX_CHUNK_COUNT`]
+    /// long. Only uploaded with [`ChunkStorage::BindingArray`].
+    pub chunk_instances: Vec<RawBufferVec<PackedFace>>,
+    pub storage: ChunkStorage,
+    /// With [`ChunkStorage::Concatenated`], the faces of every slot in slot
+    /// order.
+    pub concatenated_faces: RawBufferVec<PackedFace>,
+    /// First face of each slot in `concatenated_faces`.
+    face_offsets: Vec<u32>,
+    /// Local-to-world transform of the chunk in each slot.
+    pub chunk_transforms: RawBufferVec<Mat4>,
+    /// `chunk_transforms` as of the previous frame, for motion vectors.
+    pub previous_chunk_transforms: RawBufferVec<Mat4>,
     pub chunks_layout: BindGroupLayout,
+    slots: HashMap<Entity, u32>,
+    free_slots: Vec<u32>,
+    /// Slots whose faces changed since the last upload.
+    changed_slots: HashSet<u32>,
+    /// Slots whose chunk is visible from any view this frame.
+    visible_slots: HashSet<u32>,
+    /// Slots whose chunk is inside the frustum of each shadow view this
+    /// frame, keyed by the light's view entity.
+    shadow_slots: HashMap<Entity, HashSet<u32>>,
+    /// [`ChunkDrawArgs`] of the visible slots followed by those of every
+    /// shadow view, rebuilt every frame.
+    pub chunk_draw_args: RawBufferVec<ChunkDrawArgs>,
+    /// Range of `chunk_draw_args` drawn by the main passes.
+    pub main_draws: Range<u32>,
+    /// Range of `chunk_draw_args` drawn by each shadow view.
+    pub shadow_draws: HashMap<Entity, Range<u32>>,
 }
 
 impl FromWorld for PulledCubesBufferArrays {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
         let render_queue = world.resource::<RenderQueue>();
+        let storage = *world.resource::<ChunkStorage>();
+
+        let chunk_instances = (0..MAX_CHUNK_COUNT)
+            .map(|_| {
+                let mut instances = RawBufferVec::new(BufferUsages::STORAGE);
+                // Every slot needs a buffer to bind, even while unoccupied.
+                if storage == ChunkStorage::BindingArray {
+                    instances.push(PackedFace::zeroed());
+                    instances.write_buffer(render_device, render_queue);
+                    instances.clear();
+                }
+                instances
+            })
+            .collect();
 
-        let mut instances1 = BufferVec::new(BufferUsages::STORAGE);
-
-        instances1.push(Cube {
-            transform: Mat4::ZERO,
-        });
-
-        let mut instances2 = BufferVec::new(BufferUsages::STORAGE);
-
-        instances2.push(Cube {
-            transform: Mat4::from_translation(Vec3::new(1., 3., 1.)),
-        });
-
-        instances2.push(Cube {
-            transform: Mat4::from_translation(Vec3::new(5., 3., 1.)),
-        });
-
-        instances1.write_buffer(render_device, render_queue);
-        instances2.write_buffer(render_device, render_queue);
+        let mut concatenated_faces = RawBufferVec::new(BufferUsages::STORAGE);
+        concatenated_faces.push(PackedFace::zeroed());
+        concatenated_faces.write_buffer(render_device, render_queue);
 
-        let chunk_instances = vec![
-            instances1,
-            instances2,
-        ];
+        let mut chunk_transforms = RawBufferVec::new(BufferUsages::STORAGE);
+        let mut previous_chunk_transforms = RawBufferVec::new(BufferUsages::STORAGE);
+        for _ in 0..MAX_CHUNK_COUNT {
+            chunk_transforms.push(Mat4::ZERO);
+            previous_chunk_transforms.push(Mat4::ZERO);
+        }
+        chunk_transforms.write_buffer(render_device, render_queue);
+        previous_chunk_transforms.write_buffer(render_device, render_queue);
 
         let chunks_layout = render_device.create_bind_group_layout(
             "chunk_arrays_buffer_layout",
-            Self::bind_group_layout_entries().as_slice(),
+            Self::bind_group_layout_entries(storage).as_slice(),
         );
 
         Self {
             chunk_instances,
+            storage,
+            concatenated_faces,
+            face_offsets: vec![0; MAX_CHUNK_COUNT as usize],
+            chunk_transforms,
+            previous_chunk_transforms,
             chunks_layout,
+            slots: HashMap::new(),
+            free_slots: (0..MAX_CHUNK_COUNT).rev().collect(),
+            changed_slots: HashSet::new(),
+            visible_slots: HashSet::new(),
+            shadow_slots: HashMap::new(),
+            chunk_draw_args: RawBufferVec::new(BufferUsages::INDIRECT),
+            main_draws: 0..0,
+            shadow_draws: HashMap::new(),
         }
     }
 }
 
 impl PulledCubesBufferArrays {
+    pub fn slot(&self, entity: Entity) -> Option<u32> {
+        self.slots.get(&entity).copied()
+    }
+
+    /// Assigns a free slot to `entity`, or returns the one it already owns.
+    /// Returns `None` when all [`MAX_CHUNK_COUNT`] slots are taken.
+    pub fn allocate(&mut self, entity: Entity) -> Option<u32> {
+        if let Some(slot) = self.slot(entity) {
+            return Some(slot);
+        }
+        let slot = self.free_slots.pop()?;
+        self.slots.insert(entity, slot);
+        Some(slot)
+    }
+
+    /// Releases the slot owned by `entity` and empties it.
+    pub fn free(&mut self, entity: Entity) -> Option<u32> {
+        let slot = self.slots.remove(&entity)?;
+        self.chunk_instances[slot as usize].clear();
+        self.chunk_transforms.set(slot, Mat4::ZERO);
+        self.previous_chunk_transforms.set(slot, Mat4::ZERO);
+        self.changed_slots.insert(slot);
+        self.visible_slots.remove(&slot);
+        for slots in self.shadow_slots.values_mut() {
+            slots.remove(&slot);
+        }
+        self.free_slots.push(slot);
+        Some(slot)
+    }
+
+    /// Replaces the faces stored in `slot`.
+    pub fn set_faces(&mut self, slot: u32, faces: impl IntoIterator<Item = PackedFace>) {
+        let instances = &mut self.chunk_instances[slot as usize];
+        instances.clear();
+        for face in faces {
+            instances.push(face);
+        }
+        self.changed_slots.insert(slot);
+    }
+
+    /// Sets the transform of `slot` for this frame, keeping the previous
+    /// one around. Expected to be called once per frame for every
+    /// occupied slot.
+    pub fn set_transform(&mut self, slot: u32, transform: Mat4) {
+        let previous = self.chunk_transforms.values()[slot as usize];
+        // Freshly allocated slots have not moved yet.
+        let previous = if previous == Mat4::ZERO { transform } else { previous };
+        self.previous_chunk_transforms.set(slot, previous);
+        self.chunk_transforms.set(slot, transform);
+    }
+
+    /// Marks `slot` to be drawn this frame.
+    pub fn set_visible(&mut self, slot: u32) {
+        self.visible_slots.insert(slot);
+    }
+
+    /// Marks `slot` to be drawn into the shadow map of `view` this frame.
+    pub fn set_shadow_visible(&mut self, view: Entity, slot: u32) {
+        self.shadow_slots.entry(view).or_default().insert(slot);
+    }
+
+    /// Occupied slots and the number of faces stored in each.
+    pub fn occupied_slots(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.slots
+            .values()
+            .map(|slot| (*slot, self.chunk_instances[*slot as usize].len() as u32))
+    }
+
+    /// Slots marked visible this frame and the faces of each, as indices
+    /// into the bound face buffer.
+    pub fn visible_slots(&self) -> impl Iterator<Item = (u32, Range<u32>)> + '_ {
+        self.with_faces(&self.visible_slots)
+    }
+
+    fn with_faces<'a>(
+        &'a self,
+        slots: &'a HashSet<u32>,
+    ) -> impl Iterator<Item = (u32, Range<u32>)> + 'a {
+        slots.iter().map(|slot| (*slot, self.slot_faces(*slot)))
+    }
+
+    /// Faces of `slot` within the buffer the shader reads them from, as of
+    /// the last upload.
+    fn slot_faces(&self, slot: u32) -> Range<u32> {
+        let start = match self.storage {
+            ChunkStorage::BindingArray => 0,
+            ChunkStorage::Concatenated => self.face_offsets[slot as usize],
+        };
+        start..start + self.chunk_instances[slot as usize].len() as u32
+    }
+
+    /// Uploads the faces of changed slots, every chunk transform and the
+    /// draw arguments of the visible and shadow-casting slots, which are
+    /// reset for the next frame.
+    pub fn write_buffers(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        match self.storage {
+            ChunkStorage::BindingArray => {
+                for slot in self.changed_slots.drain() {
+                    self.chunk_instances[slot as usize].write_buffer(render_device, render_queue);
+                }
+            }
+            ChunkStorage::Concatenated => {
+                if !self.changed_slots.is_empty() {
+                    self.changed_slots.clear();
+                    self.concatenate_faces();
+                    self.concatenated_faces.write_buffer(render_device, render_queue);
+                }
+            }
+        }
+        self.chunk_transforms.write_buffer(render_device, render_queue);
+        self.previous_chunk_transforms.write_buffer(render_device, render_queue);
+
+        let mut draw_args = chunk_draw_args(self.visible_slots());
+        self.main_draws = 0..draw_args.len() as u32;
+
+        self.shadow_draws.clear();
+        for (view, slots) in &self.shadow_slots {
+            let start = draw_args.len() as u32;
+            draw_args.extend(chunk_draw_args(self.with_faces(slots)));
+            self.shadow_draws.insert(*view, start..draw_args.len() as u32);
+        }
+
+        self.visible_slots.clear();
+        self.shadow_slots.clear();
+
+        self.chunk_draw_args.clear();
+        for args in draw_args {
+            self.chunk_draw_args.push(args);
+        }
+        self.chunk_draw_args.write_buffer(render_device, render_queue);
+    }
+
+    /// Rebuilds `concatenated_faces` from the faces of every slot.
+    fn concatenate_faces(&mut self) {
+        self.concatenated_faces.clear();
+        for (slot, faces) in self.chunk_instances.iter().enumerate() {
+            self.face_offsets[slot] = self.concatenated_faces.len() as u32;
+            for face in faces.values() {
+                self.concatenated_faces.push(*face);
+            }
+        }
+        // Storage bindings cannot be empty.
+        if self.concatenated_faces.is_empty() {
+            self.concatenated_faces.push(PackedFace::zeroed());
+        }
+    }
 
     pub fn as_bind_group(
             &self,
             render_device: &RenderDevice,
         ) -> BindGroup {
-        let handles = self
-            .chunk_instances
-            .iter()
-            .map(|buffer| buffer
-                    .buffer()
-                    .unwrap()
-                    .as_entire_buffer_binding())
-            .collect::<Vec<_>>();
+        let handles = match self.storage {
+            ChunkStorage::BindingArray => self
+                .chunk_instances
+                .iter()
+                .map(|buffer| buffer
+                        .buffer()
+                        .unwrap()
+                        .as_entire_buffer_binding())
+                .collect::<Vec<_>>(),
+            ChunkStorage::Concatenated => Vec::new(),
+        };
+
+        let faces = match self.storage {
+            ChunkStorage::BindingArray => BindingResource::BufferArray(handles.as_slice()),
+            ChunkStorage::Concatenated => {
+                self.concatenated_faces.buffer().unwrap().as_entire_binding()
+            }
+        };
 
         render_device.create_bind_group(
             "main_chunk_buffers",
             &self.chunks_layout,
-            &BindGroupEntries::single(BindingResource::BufferArray(handles.as_slice()))
+            &BindGroupEntries::sequential((
+                faces,
+                self.chunk_transforms.buffer().unwrap().as_entire_binding(),
+                self.previous_chunk_transforms.buffer().unwrap().as_entire_binding(),
+            ))
         )
     }
 
-    pub fn bind_group_layout_entries()
+    pub fn bind_group_layout_entries(storage: ChunkStorage)
         -> Vec<BindGroupLayoutEntry>
         where Self: Sized {
         vec![
@@ -108,7 +363,34 @@ impl PulledCubesBufferArrays {
                     has_dynamic_offset: false,
                     min_binding_size: None,
                 },
-                count: NonZero::new(MAX_CHUNK_COUNT),
+                count: match storage {
+                    ChunkStorage::BindingArray => NonZero::new(MAX_CHUNK_COUNT),
+                    ChunkStorage::Concatenated => None,
+                },
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage {
+                        read_only: true,
+                    },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage {
+                        read_only: true,
+                    },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
             },
         ]
     }
@@ -128,20 +410,120 @@ impl Default for Cube {
     }
 }
 
-pub trait ToCubes {
-    fn to_cubes(&self) -> Vec<Cube>;
+/// Chunk face instance packed into two words.
+///
+/// `position`: x, y, z (5 bits each), direction (3 bits), width - 1 and
+/// height - 1 (5 bits each), from the least significant bit.
+///
+/// `block`: block id (16 bits), ambient occlusion (8 bits), from the least
+/// significant bit. The chunk is implied by the buffer slot the face is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C)]
+pub struct PackedFace {
+    pub position: u32,
+    pub block: u32,
+}
+
+impl PackedFace {
+    /// Largest chunk size whose local coordinates and quad extents fit.
+    pub const MAX_CHUNK_SIZE: usize = 32;
+
+    pub fn new(quad: &Quad) -> Self {
+        debug_assert!(quad.pos.cmpge(IVec3::ZERO).all() && quad.pos.cmplt(IVec3::splat(32)).all());
+        debug_assert!((1..=32).contains(&quad.width) && (1..=32).contains(&quad.height));
+
+        let position = quad.pos.x as u32
+            | (quad.pos.y as u32) << 5
+            | (quad.pos.z as u32) << 10
+            | (quad.direction as u32) << 15
+            | (quad.width - 1) << 18
+            | (quad.height - 1) << 23;
+
+        let block = quad.block.id() as u32 | (quad.ao as u32) << 16;
+
+        Self { position, block }
+    }
+
+    pub fn pos(&self) -> IVec3 {
+        IVec3::new(
+            (self.position & 0x1f) as i32,
+            ((self.position >> 5) & 0x1f) as i32,
+            ((self.position >> 10) & 0x1f) as i32,
+        )
+    }
+
+    pub fn direction(&self) -> FaceDirection {
+        FaceDirection::ALL[((self.position >> 15) & 0x7) as usize]
+    }
+
+    pub fn width(&self) -> u32 {
+        ((self.position >> 18) & 0x1f) + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        ((self.position >> 23) & 0x1f) + 1
+    }
+
+    pub fn block(&self) -> BlockState {
+        BlockState((self.block & 0xffff) as u16)
+    }
+
+    pub fn ao(&self) -> u8 {
+        (self.block >> 16) as u8
+    }
+}
+
+/// Arguments of one non-indexed indirect draw, laid out as expected by
+/// `draw_indirect` and `multi_draw_indirect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[repr(C)]
+pub struct ChunkDrawArgs {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub first_vertex: u32,
+    pub first_instance: u32,
+}
+
+/// Builds the indirect draw of every `(slot, faces)` pair, in slot order,
+/// where `faces` indexes the buffer the slot's faces are read from. Slots
+/// without faces are skipped.
+///
+/// The shader picks the chunk slot from the instance index, so every draw
+/// is a single instance starting at `FIRST_CHUNK_INSTANCE + slot`.
+pub fn chunk_draw_args(slots: impl IntoIterator<Item = (u32, Range<u32>)>) -> Vec<ChunkDrawArgs> {
+    let mut args = slots
+        .into_iter()
+        .filter(|(_, faces)| !faces.is_empty())
+        .map(|(slot, faces)| ChunkDrawArgs {
+            vertex_count: faces.len() as u32 * VERTICES_PER_FACE,
+            instance_count: 1,
+            first_vertex: faces.start * VERTICES_PER_FACE,
+            first_instance: FIRST_CHUNK_INSTANCE + slot,
+        })
+        .collect::<Vec<_>>();
+    args.sort_unstable_by_key(|args| args.first_instance);
+    args
+}
+
+fn material_color(material: &BlockMaterial) -> Vec4 {
+    match material {
+        BlockMaterial::None => Vec4::ZERO,
+        BlockMaterial::Color(color) => LinearRgba::from(*color).to_vec4(),
+    }
 }
 
 pub fn update_buffers(
     mut buffers: ResMut<PulledCubesBuffers>,
+    registry: Res<BlockRegistry>,
     cubes: Query<(&PulledCube, &Transform, &ViewVisibility)>,
-    chunks: Query<(&ExtractedChunk<16>, &Transform, &ViewVisibility)>,
 ) {
-    if !buffers.dirty {
-        return;
+    if registry.is_changed() {
+        buffers.materials.clear();
+        for (_, block) in registry.iter() {
+            buffers.materials.push(material_color(&block.material));
+        }
+        buffers.dirty = true;
     }
-    
-    buffers.dirty = true;
 
     buffers.instances.clear();
 
@@ -154,80 +536,135 @@ pub fn update_buffers(
             transform: transform.compute_matrix().transpose(),
         });
     }
-
-    for (chunk, transform, _visibility) in &chunks {
-        // if !visibility.get() {
-        //     continue;
-        // }
-
-        let matrix = transform.compute_matrix().transpose();
-
-        chunk
-            .to_cubes()
-            .iter_mut()
-            .map(|cube| {
-                cube.transform *= matrix;
-                cube
-            })
-            .for_each(|cube| {
-                buffers.instances.push(*cube);
-            });
-    }
 }
 
+/// Re-meshes chunks whose blocks changed, along with their neighbours, and
+/// keeps every chunk's slot and transform up to date.
+///
+/// Chunks outside every view and shadow map keep their slot but are neither
+/// re-meshed nor drawn; pending re-meshes are deferred until they become
+/// visible again.
+#[allow(clippy::too_many_arguments)]
 pub fn update_chunk_buffers<const SIZE: usize>(
-    mut buffers: ResMut<PulledCubesBuffers>,
-    chunks: Query<(&ExtractedChunk<SIZE>, &Transform, &ViewVisibility)>,
+    buffers: Res<PulledCubesBuffers>,
+    mut arrays: ResMut<PulledCubesBufferArrays>,
+    registry: Res<BlockRegistry>,
+    meshing: Res<ChunkMeshing<SIZE>>,
+    counter: Res<ChunkRemeshCounter>,
+    chunks: Query<(Entity, Ref<ExtractedChunk<SIZE>>, &GlobalTransform, &ViewVisibility)>,
+    mut removed: RemovedComponents<ExtractedChunk<SIZE>>,
+    shadow_views: Query<(Entity, &Frustum, &LightEntity)>,
+    mut offsets: Local<HashMap<Entity, (Option<Entity>, I64Vec3)>>,
+    mut stale: Local<HashSet<Entity>>,
 ) {
-    if !buffers.dirty {
-        return;
+    // Levels and chunk coordinates whose blocks changed, appeared or
+    // disappeared, so the faces along their borders have to be rebuilt too.
+    let mut changed = HashSet::new();
+
+    for entity in removed.read() {
+        arrays.free(entity);
+        stale.remove(&entity);
+        if let Some(offset) = offsets.remove(&entity) {
+            changed.insert(offset);
+        }
     }
-    
-    buffers.dirty = false;
 
-    for (chunk, transform, _) in &chunks {
-        // if !visibility.get() {
-        //     continue;
-        // }
+    for (entity, chunk, _, _) in &chunks {
+        if !chunk.is_changed() {
+            continue;
+        }
+        if let Some(offset) = chunk.offset {
+            changed.insert((chunk.level, offset));
+            offsets.insert(entity, (chunk.level, offset));
+        }
+    }
+
+    let by_offset = chunks
+        .iter()
+        .filter_map(|(_, chunk, _, _)| {
+            chunk
+                .offset
+                .map(|offset| ((chunk.level, offset), chunk.into_inner()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut remeshed = 0;
+    let aabb = Chunk::<SIZE>::aabb();
+
+    for (entity, chunk, transform, visibility) in &chunks {
+        let is_new = arrays.slot(entity).is_none();
+        let Some(slot) = arrays.allocate(entity) else {
+            warn_once!("more than {MAX_CHUNK_COUNT} chunks are loaded, the rest are not rendered");
+            // Chunks already holding a slot still need updating.
+            continue;
+        };
+
+        let world_from_local = transform.affine();
+        arrays.set_transform(slot, Mat4::from(world_from_local));
+
+        let mut casts_shadow = false;
+        for (view, frustum, light) in &shadow_views {
+            // Casters in front of a cascade's near plane still cast shadows
+            // into it, as in Bevy's own culling.
+            let intersect_near = !matches!(light, LightEntity::Directional { .. });
+            if frustum.intersects_obb(&aabb, &world_from_local, intersect_near, true) {
+                arrays.set_shadow_visible(view, slot);
+                casts_shadow = true;
+            }
+        }
 
-        let matrix = transform.compute_matrix().transpose();
+        let neighbour_changed = chunk.offset.is_some_and(|offset| {
+            FaceDirection::ALL
+                .into_iter()
+                .any(|direction| changed.contains(&(chunk.level, offset + direction.normal().as_i64vec3())))
+        });
 
-        chunk
-            .to_cubes()
-            .iter_mut()
-            .map(|cube| {
-                cube.transform *= matrix;
-                cube
-            })
-            .for_each(|cube| {
-                buffers.instances.push(*cube);
-            });
+        let needs_remesh = buffers.dirty || is_new || chunk.is_changed() || neighbour_changed;
+
+        if visibility.get() {
+            arrays.set_visible(slot);
+        } else if !casts_shadow {
+            if needs_remesh {
+                stale.insert(entity);
+            }
+            continue;
+        }
+
+        if !(stale.remove(&entity) || needs_remesh) {
+            continue;
+        }
+
+        let mut neighbours = ChunkNeighbours::default();
+        if let Some(offset) = chunk.offset {
+            for direction in FaceDirection::ALL {
+                neighbours.chunks[direction as usize] = by_offset
+                    .get(&(chunk.level, offset + direction.normal().as_i64vec3()))
+                    .map(|neighbour| &neighbour.blocks);
+            }
+        }
+
+        arrays.set_faces(slot, chunk.to_packed_faces(&registry, &neighbours, meshing.0));
+        remeshed += 1;
     }
+
+    counter.add(remeshed);
 }
 
 pub fn write_buffers(
     mut buffers: ResMut<PulledCubesBuffers>,
+    mut arrays: ResMut<PulledCubesBufferArrays>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     mut pipeline: ResMut<CubePullingPipeline>,
-//    mut shadow_pipeline: ResMut<CubePullingShadowPipeline>,
 ) {
-    buffers
-        .instances
-        .write_buffer(&render_device, &render_queue);
-
-
-    pipeline.bind_group = create_bind_group(
-            &render_device,
-            &pipeline.layout,
-            buffers.instances.buffer().unwrap(),
-        );
+    let buffers = buffers.as_mut();
+    buffers.instances.write_buffer(&render_device, &render_queue);
+    buffers.materials.write_buffer(&render_device, &render_queue);
+    arrays.write_buffers(&render_device, &render_queue);
+    buffers.dirty = false;
 
-//        shadow_pipeline.bind_group = create_bind_group(
-//            &render_device,
-//            &pipeline.layout,
-//            buffers.instances.buffer().unwrap(),
-//        );
+    pipeline.bind_group = create_bind_group(&render_device, &pipeline.layout, buffers);
+    pipeline.chunks_bind_group = arrays.as_bind_group(&render_device);
 }
 
 impl FromWorld for PulledCubesBuffers {
@@ -236,15 +673,21 @@ impl FromWorld for PulledCubesBuffers {
         let render_queue = world.resource::<RenderQueue>();
 
         let mut instances = BufferVec::new(BufferUsages::STORAGE);
+        let mut materials = BufferVec::new(BufferUsages::STORAGE);
 
+        // Storage bindings cannot be empty, so every buffer starts with a
+        // placeholder element.
         instances.push(Cube {
             transform: Mat4::ZERO,
         });
+        materials.push(Vec4::ZERO);
 
         instances.write_buffer(render_device, render_queue);
+        materials.write_buffer(render_device, render_queue);
 
         PulledCubesBuffers {
             instances,
+            materials,
             dirty: true,
         }
     }
@@ -253,3 +696,60 @@ impl FromWorld for PulledCubesBuffers {
 pub(crate) fn prepare_custom_phase_item_buffers(mut commands: Commands) {
     commands.init_resource::<PulledCubesBuffers>();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_slots_without_faces() {
+        let args = chunk_draw_args([(0, 0..0), (1, 0..3), (2, 5..5)]);
+        assert_eq!(args.len(), 1);
+        assert_eq!(args[0].first_instance, FIRST_CHUNK_INSTANCE + 1);
+    }
+
+    #[test]
+    fn sorts_by_slot() {
+        let args = chunk_draw_args([(7, 0..1), (2, 0..1), (4, 0..1)]);
+        let slots = args
+            .iter()
+            .map(|args| args.first_instance - FIRST_CHUNK_INSTANCE)
+            .collect::<Vec<_>>();
+        assert_eq!(slots, [2, 4, 7]);
+    }
+
+    /// With [`ChunkStorage::BindingArray`], every slot reads its own buffer
+    /// from the start.
+    #[test]
+    fn draws_one_instance_per_slot() {
+        let args = chunk_draw_args([(3, 0..10)]);
+        assert_eq!(
+            args,
+            [ChunkDrawArgs {
+                vertex_count: 10 * VERTICES_PER_FACE,
+                instance_count: 1,
+                first_vertex: 0,
+                first_instance: FIRST_CHUNK_INSTANCE + 3,
+            }]
+        );
+    }
+
+    /// With [`ChunkStorage::Concatenated`], each slot starts where the faces
+    /// of the previous ones end.
+    #[test]
+    fn offsets_concatenated_faces() {
+        let args = chunk_draw_args([(2, 7..7), (0, 0..4), (1, 4..7), (3, 7..9)]);
+        let draws = args
+            .iter()
+            .map(|args| (args.first_vertex, args.vertex_count))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            draws,
+            [
+                (0, 4 * VERTICES_PER_FACE),
+                (4 * VERTICES_PER_FACE, 3 * VERTICES_PER_FACE),
+                (7 * VERTICES_PER_FACE, 2 * VERTICES_PER_FACE),
+            ]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic},
+    prelude::*,
+    render::RenderApp,
+};
+
+/// Number of chunks re-meshed and re-uploaded by the renderer per frame.
+pub const CHUNKS_REMESHED: DiagnosticPath = DiagnosticPath::const_new("vkxl/chunks_remeshed");
+
+/// Counts remeshed chunks in the render world until the main world records
+/// them as a [`CHUNKS_REMESHED`] measurement.
+#[derive(Resource, Clone, Default)]
+pub struct ChunkRemeshCounter(Arc<AtomicU32>);
+
+impl ChunkRemeshCounter {
+    pub fn add(&self, count: u32) {
+        self.0.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> u32 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct ChunkDiagnosticsPlugin;
+
+impl Plugin for ChunkDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        let counter = ChunkRemeshCounter::default();
+
+        app.register_diagnostic(Diagnostic::new(CHUNKS_REMESHED))
+            .insert_resource(counter.clone())
+            .add_systems(Update, record_chunk_diagnostics);
+
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.insert_resource(counter);
+        }
+    }
+}
+
+fn record_chunk_diagnostics(counter: Res<ChunkRemeshCounter>, mut diagnostics: Diagnostics) {
+    diagnostics.add_measurement(&CHUNKS_REMESHED, || counter.take() as f64);
+}
@@ -0,0 +1,474 @@
+use bevy::math::IVec3;
+
+use crate::world::{
+    block::{BlockRegistry, BlockState},
+    palette::PalettedContainer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FaceDirection {
+    PosX = 0,
+    NegX = 1,
+    PosY = 2,
+    NegY = 3,
+    PosZ = 4,
+    NegZ = 5,
+}
+
+impl FaceDirection {
+    pub const ALL: [FaceDirection; 6] = [
+        FaceDirection::PosX,
+        FaceDirection::NegX,
+        FaceDirection::PosY,
+        FaceDirection::NegY,
+        FaceDirection::PosZ,
+        FaceDirection::NegZ,
+    ];
+
+    pub const fn normal(self) -> IVec3 {
+        match self {
+            FaceDirection::PosX => IVec3::X,
+            FaceDirection::NegX => IVec3::NEG_X,
+            FaceDirection::PosY => IVec3::Y,
+            FaceDirection::NegY => IVec3::NEG_Y,
+            FaceDirection::PosZ => IVec3::Z,
+            FaceDirection::NegZ => IVec3::NEG_Z,
+        }
+    }
+
+    /// Axis the face is perpendicular to, `0` for x, `1` for y, `2` for z.
+    pub const fn axis(self) -> usize {
+        self as usize / 2
+    }
+
+    pub const fn opposite(self) -> Self {
+        match self {
+            FaceDirection::PosX => FaceDirection::NegX,
+            FaceDirection::NegX => FaceDirection::PosX,
+            FaceDirection::PosY => FaceDirection::NegY,
+            FaceDirection::NegY => FaceDirection::PosY,
+            FaceDirection::PosZ => FaceDirection::NegZ,
+            FaceDirection::NegZ => FaceDirection::PosZ,
+        }
+    }
+}
+
+/// A single block face that is not hidden by its neighbour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Face {
+    /// Block position in chunk-local coordinates.
+    pub pos: IVec3,
+    pub direction: FaceDirection,
+    pub block: BlockState,
+    /// Ambient occlusion level (`0..=3`) of each corner, two bits per
+    /// corner, in the corner order of [`Quad::corners`].
+    pub ao: u8,
+}
+
+/// Block storage of the six chunks bordering the one being meshed, indexed
+/// by [`FaceDirection`]. Missing neighbours are treated as empty, so faces
+/// on that border are always emitted.
+#[derive(Default, Clone, Copy)]
+pub struct ChunkNeighbours<'a> {
+    pub chunks: [Option<&'a PalettedContainer<BlockState>>; 6],
+}
+
+impl<'a> ChunkNeighbours<'a> {
+    pub fn get(&self, direction: FaceDirection) -> Option<&'a PalettedContainer<BlockState>> {
+        self.chunks[direction as usize]
+    }
+}
+
+fn index(pos: IVec3, size: usize) -> usize {
+    pos.x as usize * size * size + pos.y as usize * size + pos.z as usize
+}
+
+fn block_at(
+    blocks: &PalettedContainer<BlockState>,
+    neighbours: &ChunkNeighbours,
+    pos: IVec3,
+    size: usize,
+) -> Option<BlockState> {
+    let limit = size as i32;
+    let outside_axes = (pos.cmplt(IVec3::ZERO) | pos.cmpge(IVec3::splat(limit))).bitmask();
+    if outside_axes.count_ones() > 1 {
+        // Diagonal neighbours are not available to the mesher.
+        return None;
+    }
+
+    let outside = if pos.x >= limit {
+        Some(FaceDirection::PosX)
+    } else if pos.x < 0 {
+        Some(FaceDirection::NegX)
+    } else if pos.y >= limit {
+        Some(FaceDirection::PosY)
+    } else if pos.y < 0 {
+        Some(FaceDirection::NegY)
+    } else if pos.z >= limit {
+        Some(FaceDirection::PosZ)
+    } else if pos.z < 0 {
+        Some(FaceDirection::NegZ)
+    } else {
+        None
+    };
+
+    match outside {
+        None => Some(*blocks.get(index(pos, size))),
+        Some(direction) => {
+            let wrapped = pos.rem_euclid(IVec3::splat(limit));
+            neighbours
+                .get(direction)
+                .map(|neighbour| *neighbour.get(index(wrapped, size)))
+        }
+    }
+}
+
+/// Whether a face of `block` is hidden by the block `neighbour` in front of it.
+pub fn is_occluded(registry: &BlockRegistry, block: BlockState, neighbour: Option<BlockState>) -> bool {
+    let Some(neighbour) = neighbour else {
+        return false;
+    };
+    neighbour == block || registry.get(neighbour).is_some_and(|neighbour| neighbour.opaque)
+}
+
+fn is_rendered(registry: &BlockRegistry, block: BlockState) -> bool {
+    registry.get(block).is_some_and(|block| block.is_rendered())
+}
+
+/// Returns the block owning the face of `pos` pointing in `direction`, if
+/// that face is visible.
+fn visible_face(
+    blocks: &PalettedContainer<BlockState>,
+    neighbours: &ChunkNeighbours,
+    registry: &BlockRegistry,
+    pos: IVec3,
+    direction: FaceDirection,
+    size: usize,
+) -> Option<BlockState> {
+    let block = *blocks.get(index(pos, size));
+    if !is_rendered(registry, block) {
+        return None;
+    }
+
+    let neighbour = block_at(blocks, neighbours, pos + direction.normal(), size);
+    (!is_occluded(registry, block, neighbour)).then_some(block)
+}
+
+/// Computes the per-corner ambient occlusion of a face from the three
+/// blocks touching each corner in front of it.
+fn ambient_occlusion(
+    blocks: &PalettedContainer<BlockState>,
+    neighbours: &ChunkNeighbours,
+    registry: &BlockRegistry,
+    pos: IVec3,
+    direction: FaceDirection,
+    size: usize,
+) -> u8 {
+    let (u, v) = Quad::axes(direction);
+    let front = pos + direction.normal();
+    let is_solid = |offset_u: i32, offset_v: i32| {
+        let mut pos = front;
+        pos[u] += offset_u;
+        pos[v] += offset_v;
+        block_at(blocks, neighbours, pos, size)
+            .and_then(|block| registry.get(block))
+            .is_some_and(|block| block.opaque)
+    };
+
+    let mut ao = 0;
+    for (corner, (du, dv)) in Quad::corners().into_iter().enumerate() {
+        let (du, dv) = (du * 2 - 1, dv * 2 - 1);
+        let side_u = is_solid(du, 0);
+        let side_v = is_solid(0, dv);
+        let level = if side_u && side_v {
+            3
+        } else {
+            side_u as u8 + side_v as u8 + is_solid(du, dv) as u8
+        };
+        ao |= level << (corner * 2);
+    }
+    ao
+}
+
+fn is_empty(blocks: &PalettedContainer<BlockState>, registry: &BlockRegistry) -> bool {
+    blocks
+        .as_uniform()
+        .is_some_and(|block| !is_rendered(registry, *block))
+}
+
+/// Emits every face of a `SIZE`³ chunk that is not hidden by an adjacent
+/// block, in block index order.
+pub fn cull_faces<const SIZE: usize>(
+    blocks: &PalettedContainer<BlockState>,
+    neighbours: &ChunkNeighbours,
+    registry: &BlockRegistry,
+) -> Vec<Face> {
+    let mut faces = Vec::new();
+
+    if is_empty(blocks, registry) {
+        return faces;
+    }
+
+    for i in 0..(SIZE * SIZE * SIZE) {
+        let pos = IVec3::new(
+            (i / (SIZE * SIZE)) as i32,
+            ((i / SIZE) % SIZE) as i32,
+            (i % SIZE) as i32,
+        );
+
+        for direction in FaceDirection::ALL {
+            if let Some(block) = visible_face(blocks, neighbours, registry, pos, direction, SIZE) {
+                faces.push(Face {
+                    pos,
+                    direction,
+                    block,
+                    ao: ambient_occlusion(blocks, neighbours, registry, pos, direction, SIZE),
+                });
+            }
+        }
+    }
+
+    faces
+}
+
+/// How chunk geometry is built from block data.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MeshingMode {
+    /// One quad per visible block face.
+    #[default]
+    Culled,
+    /// Coplanar visible faces of the same block are merged into larger quads.
+    Greedy,
+}
+
+/// A rectangle of merged, coplanar faces of the same block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quad {
+    /// Chunk-local position of the block at the quad's minimum corner.
+    pub pos: IVec3,
+    pub direction: FaceDirection,
+    /// Extent along the first in-plane axis, see [`Quad::axes`].
+    pub width: u32,
+    /// Extent along the second in-plane axis, see [`Quad::axes`].
+    pub height: u32,
+    pub block: BlockState,
+    /// Per-corner ambient occlusion, see [`Face::ao`].
+    pub ao: u8,
+}
+
+impl Quad {
+    /// Corners of a quad as `(u, v)` offsets, in the order used for ambient
+    /// occlusion bits and by the shader.
+    pub const fn corners() -> [(i32, i32); 4] {
+        [(0, 0), (1, 0), (1, 1), (0, 1)]
+    }
+
+    /// In-plane axes spanned by `width` and `height`, respectively.
+    pub const fn axes(direction: FaceDirection) -> (usize, usize) {
+        let axis = direction.axis();
+        ((axis + 1) % 3, (axis + 2) % 3)
+    }
+
+    /// Size of the block-aligned box whose face this quad covers.
+    pub fn extent(&self) -> IVec3 {
+        let (u, v) = Self::axes(self.direction);
+        let mut extent = IVec3::ONE;
+        extent[u] = self.width as i32;
+        extent[v] = self.height as i32;
+        extent
+    }
+
+    pub fn area(&self) -> u32 {
+        self.width * self.height
+    }
+}
+
+impl From<Face> for Quad {
+    fn from(face: Face) -> Self {
+        Self {
+            pos: face.pos,
+            direction: face.direction,
+            width: 1,
+            height: 1,
+            block: face.block,
+            ao: face.ao,
+        }
+    }
+}
+
+/// Emits the visible faces of a `SIZE`³ chunk, greedily merging adjacent
+/// coplanar faces of the same block into quads. Merged quads carry no
+/// ambient occlusion.
+pub fn greedy_quads<const SIZE: usize>(
+    blocks: &PalettedContainer<BlockState>,
+    neighbours: &ChunkNeighbours,
+    registry: &BlockRegistry,
+) -> Vec<Quad> {
+    let mut quads = Vec::new();
+
+    if is_empty(blocks, registry) {
+        return quads;
+    }
+
+    let mut mask = vec![None; SIZE * SIZE];
+
+    for direction in FaceDirection::ALL {
+        let axis = direction.axis();
+        let (u, v) = Quad::axes(direction);
+
+        for layer in 0..SIZE {
+            let slice_pos = |a: usize, b: usize| {
+                let mut pos = IVec3::ZERO;
+                pos[axis] = layer as i32;
+                pos[u] = a as i32;
+                pos[v] = b as i32;
+                pos
+            };
+
+            for a in 0..SIZE {
+                for b in 0..SIZE {
+                    mask[a * SIZE + b] = visible_face(
+                        blocks,
+                        neighbours,
+                        registry,
+                        slice_pos(a, b),
+                        direction,
+                        SIZE,
+                    );
+                }
+            }
+
+            for a in 0..SIZE {
+                let mut b = 0;
+                while b < SIZE {
+                    let Some(block) = mask[a * SIZE + b] else {
+                        b += 1;
+                        continue;
+                    };
+
+                    let mut height = 1;
+                    while b + height < SIZE && mask[a * SIZE + b + height] == Some(block) {
+                        height += 1;
+                    }
+
+                    let mut width = 1;
+                    while a + width < SIZE
+                        && (b..b + height).all(|k| mask[(a + width) * SIZE + k] == Some(block))
+                    {
+                        width += 1;
+                    }
+
+                    for row in a..a + width {
+                        mask[row * SIZE + b..row * SIZE + b + height].fill(None);
+                    }
+
+                    quads.push(Quad {
+                        pos: slice_pos(a, b),
+                        direction,
+                        width: width as u32,
+                        height: height as u32,
+                        block,
+                        ao: 0,
+                    });
+
+                    b += height;
+                }
+            }
+        }
+    }
+
+    quads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 8;
+
+    fn chunk(solid: impl Fn(IVec3) -> bool) -> PalettedContainer<BlockState> {
+        let mut blocks = PalettedContainer::new(SIZE * SIZE * SIZE, BlockState::AIR);
+        for i in 0..SIZE * SIZE * SIZE {
+            let pos = IVec3::new(
+                (i / (SIZE * SIZE)) as i32,
+                ((i / SIZE) % SIZE) as i32,
+                (i % SIZE) as i32,
+            );
+            if solid(pos) {
+                blocks.set(i, BlockState::STONE);
+            }
+        }
+        blocks
+    }
+
+    fn faces(blocks: &PalettedContainer<BlockState>, neighbours: &ChunkNeighbours) -> Vec<Face> {
+        cull_faces::<SIZE>(blocks, neighbours, &BlockRegistry::default())
+    }
+
+    #[test]
+    fn single_block_has_six_faces() {
+        let blocks = chunk(|pos| pos == IVec3::splat(3));
+        let faces = faces(&blocks, &ChunkNeighbours::default());
+        assert_eq!(faces.len(), 6);
+        assert!(faces.iter().all(|face| face.pos == IVec3::splat(3) && face.ao == 0));
+    }
+
+    #[test]
+    fn buried_blocks_have_no_faces() {
+        let blocks = chunk(|_| true);
+        let faces = faces(&blocks, &ChunkNeighbours::default());
+        // Only the faces on the chunk border, with no neighbours loaded.
+        assert_eq!(faces.len(), 6 * SIZE * SIZE);
+        assert!(!faces.iter().any(|face| face.pos == IVec3::splat(3)));
+    }
+
+    #[test]
+    fn empty_chunk_has_no_faces() {
+        let blocks = chunk(|_| false);
+        assert!(faces(&blocks, &ChunkNeighbours::default()).is_empty());
+    }
+
+    #[test]
+    fn culls_across_chunk_borders() {
+        let edge = SIZE as i32 - 1;
+        let blocks = chunk(|pos| pos == IVec3::new(edge, 3, 3));
+        let neighbour = chunk(|pos| pos == IVec3::new(0, 3, 3));
+        let empty_neighbour = chunk(|_| false);
+
+        let mut neighbours = ChunkNeighbours::default();
+        assert_eq!(faces(&blocks, &neighbours).len(), 6);
+
+        neighbours.chunks[FaceDirection::PosX as usize] = Some(&empty_neighbour);
+        assert_eq!(faces(&blocks, &neighbours).len(), 6);
+
+        neighbours.chunks[FaceDirection::PosX as usize] = Some(&neighbour);
+        let faces = faces(&blocks, &neighbours);
+        assert_eq!(faces.len(), 5);
+        assert!(!faces.iter().any(|face| face.direction == FaceDirection::PosX));
+    }
+
+    #[test]
+    fn greedy_merges_slab_into_one_quad_per_side() {
+        let blocks = chunk(|pos| pos.y == 2);
+        let quads = greedy_quads::<SIZE>(&blocks, &ChunkNeighbours::default(), &BlockRegistry::default());
+
+        assert_eq!(quads.len(), 6);
+        for direction in FaceDirection::ALL {
+            let quad = quads.iter().find(|quad| quad.direction == direction).unwrap();
+            let expected = if direction.axis() == 1 { SIZE * SIZE } else { SIZE };
+            assert_eq!(quad.area() as usize, expected, "{direction:?}");
+        }
+    }
+
+    #[test]
+    fn greedy_covers_the_same_faces() {
+        let blocks = chunk(|pos| (pos.x * 3 + pos.y * 5 + pos.z * 7) % 4 == 0 || pos.y < 2);
+        let faces = faces(&blocks, &ChunkNeighbours::default());
+        let quads = greedy_quads::<SIZE>(&blocks, &ChunkNeighbours::default(), &BlockRegistry::default());
+
+        assert!(quads.len() < faces.len());
+        let area = quads.iter().map(|quad| quad.area() as usize).sum::<usize>();
+        assert_eq!(area, faces.len());
+    }
+}
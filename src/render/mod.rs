@@ -1,36 +1,66 @@
 use bevy::{
     app::{App, Plugin},
-    core_pipeline::core_3d::Opaque3d,
+    core_pipeline::{
+        core_3d::Opaque3d,
+        prepass::Opaque3dPrepass,
+    },
+    pbr::Shadow,
     prelude::*,
     render::{
-        extract_component::{ExtractComponent, ExtractComponentPlugin}, render_phase::AddRenderCommand, render_resource::SpecializedRenderPipelines, renderer::RenderDevice, settings::WgpuFeatures, Render, RenderApp, RenderSet
+        extract_component::{ExtractComponent, ExtractComponentPlugin}, extract_resource::ExtractResourcePlugin, render_phase::AddRenderCommand, render_resource::SpecializedRenderPipelines, renderer::RenderDevice, view::{self, VisibilityClass}, Render, RenderApp, RenderSet
     },
 };
-use buffers::{prepare_custom_phase_item_buffers, update_buffers, write_buffers, PulledCubesBuffers, PulledCubesBufferArrays};
+use diagnostics::ChunkDiagnosticsPlugin;
+use buffers::{prepare_custom_phase_item_buffers, update_buffers, write_buffers, ChunkStorage, PulledCubesBuffers, PulledCubesBufferArrays};
 use pipeline::{
-    queue_custom_phase_item, CubePullingPipeline,
-    DrawPulledCubesCommands,
+    queue_custom_phase_item, ChunkDrawMode, CubePullingPipeline, CubePullingPrepassPipeline,
+    DrawPulledCubesCommands, DrawPulledCubesPrepassCommands,
 };
 
-use crate::world::chunk::ChunkPlugin;
+use crate::world::{block::BlockRegistry, chunk::ChunkSizes};
 
 pub mod buffers;
+pub mod diagnostics;
+pub mod mesher;
 pub mod pipeline;
 
-pub struct VoxelRendererPlugin;
+/// Renders [`PulledCube`]s and chunks of every size simulated by
+/// [`VoxelPlugin`](crate::VoxelPlugin), which has to be added first.
+///
+/// Without a [`RenderApp`], e.g. with `WgpuSettings { backends: None, .. }`,
+/// only the main world side is set up.
+#[derive(Default)]
+pub struct VoxelRendererPlugin {
+    /// Overrides how chunk faces are bound. By default binding arrays are
+    /// used when the device supports them.
+    pub chunk_storage: Option<ChunkStorage>,
+}
 
 impl Plugin for VoxelRendererPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
             ExtractComponentPlugin::<PulledCube>::default(),
-            ChunkPlugin::<16>,
-            GpuFeatureSupportChecker,
+            ExtractResourcePlugin::<BlockRegistry>::default(),
+            ChunkDiagnosticsPlugin,
         ));
 
-        app.get_sub_app_mut(RenderApp)
-            .unwrap()
+        let chunk_sizes = app
+            .world()
+            .get_resource::<ChunkSizes>()
+            .cloned()
+            .expect("VoxelPlugin has to be added before VoxelRendererPlugin");
+        for chunk_size in &chunk_sizes.0 {
+            chunk_size.render(app);
+        }
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
             .add_render_command::<Opaque3d, DrawPulledCubesCommands>()
-//            .add_render_command::<Shadow, DrawPulledCubesPrepassCommands>()
+            .add_render_command::<Opaque3dPrepass, DrawPulledCubesPrepassCommands>()
+            .add_render_command::<Shadow, DrawPulledCubesPrepassCommands>()
             .add_systems(
                 Render,
                 prepare_custom_phase_item_buffers.in_set(RenderSet::Prepare),
@@ -39,54 +69,43 @@ impl Plugin for VoxelRendererPlugin {
                 Render,
                 (
                     queue_custom_phase_item.in_set(RenderSet::Queue),
-                    update_buffers,
-                    write_buffers,
-                    ).chain(),
+                    (update_buffers, write_buffers)
+                        .chain()
+                        .in_set(RenderSet::PrepareResources),
+                ),
             );
     }
 
-    fn finish(&self, app: &mut App) {
-        app.get_sub_app_mut(RenderApp)
-            .expect("RenderApp does not exist")
-            .init_resource::<PulledCubesBufferArrays>()
-            .init_resource::<PulledCubesBuffers>()
-            .init_resource::<CubePullingPipeline>()
-//            .init_resource::<CubePullingShadowPipeline>()
-            .init_resource::<SpecializedRenderPipelines<CubePullingPipeline>>();
-//            .init_resource::<SpecializedRenderPipelines<CubePullingShadowPipeline>>();
-    }
-}
-
-
-struct GpuFeatureSupportChecker;
-
-impl Plugin for GpuFeatureSupportChecker {
-    fn build(&self, _app: &mut App) {}
-
     fn finish(&self, app: &mut App) {
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
         };
 
         let render_device = render_app.world().resource::<RenderDevice>();
+        let chunk_storage = self
+            .chunk_storage
+            .unwrap_or_else(|| ChunkStorage::for_device(render_device));
+        let draw_mode = ChunkDrawMode::for_device(render_device);
 
-        // Check if the device support the required feature. If not, exit the example.
-        // In a real application, you should setup a fallback for the missing feature
-        if !render_device
-            .features()
-            .contains(WgpuFeatures::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING)
-        {
-            panic!(
-"Render device doesn't support feature \
-SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING, \
-which is required for texture binding arrays"
-            );
+        if chunk_storage != ChunkStorage::BindingArray || draw_mode != ChunkDrawMode::MultiDrawIndirect {
+            info!("voxel renderer using {chunk_storage:?} chunk storage and {draw_mode:?} chunk draws");
         }
+
+        render_app
+            .insert_resource(chunk_storage)
+            .insert_resource(draw_mode)
+            .init_resource::<PulledCubesBufferArrays>()
+            .init_resource::<PulledCubesBuffers>()
+            .init_resource::<CubePullingPipeline>()
+            .init_resource::<CubePullingPrepassPipeline>()
+            .init_resource::<SpecializedRenderPipelines<CubePullingPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<CubePullingPrepassPipeline>>();
     }
 }
 
 #[derive(Clone, Component)]
-#[require(Transform)]
+#[require(Transform, VisibilityClass)]
+#[component(on_add = view::add_visibility_class::<PulledCube>)]
 pub struct PulledCube;
 
 impl ExtractComponent for PulledCube {
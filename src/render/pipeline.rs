@@ -1,41 +1,49 @@
 
+use std::ops::Range;
+
 use bevy::{
     asset::Handle,
-    core_pipeline::core_3d::{Opaque3d, Opaque3dBatchSetKey, Opaque3dBinKey, CORE_3D_DEPTH_FORMAT},
+    core_pipeline::{
+        core_3d::{Opaque3d, Opaque3dBatchSetKey, Opaque3dBinKey, CORE_3D_DEPTH_FORMAT},
+        prepass::{
+            prepass_target_descriptors, Opaque3dPrepass, OpaqueNoLightmap3dBatchSetKey,
+            OpaqueNoLightmap3dBinKey,
+        },
+    },
     ecs::{
         component::Tick, query::ROQueryItem, system::{lifetimeless::SRes, SystemParamItem}
     },
     pbr::{
-        ExtractedDirectionalLight, ExtractedPointLight, LightEntity, MeshPipeline, MeshPipelineKey,
-        RenderCascadesVisibleEntities, RenderCubemapVisibleEntities, RenderVisibleMeshEntities,
-        SetMeshViewBindGroup, SetPrepassViewBindGroup, ViewLightEntities,
+        LightEntity, MeshPipeline, MeshPipelineKey, PrepassPipeline, SetMeshViewBindGroup,
+        SetPrepassViewBindGroup, Shadow, ShadowBatchSetKey, ShadowBinKey, ViewKeyCache,
+        ViewLightEntities, TONEMAPPING_LUT_SAMPLER_BINDING_INDEX,
+        TONEMAPPING_LUT_TEXTURE_BINDING_INDEX,
     },
     prelude::*,
     render::{
-        globals::GlobalsUniform,
         render_phase::{
             BinnedRenderPhaseType, DrawFunctions, InputUniformIndex, PhaseItem, RenderCommand, RenderCommandResult, SetItemPipeline, TrackedRenderPass, ViewBinnedRenderPhases
         },
         render_resource::{
-            binding_types::uniform_buffer, BindGroup, BindGroupEntry, BindGroupLayout,
-            BindGroupLayoutEntries, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
-            BufferBinding, BufferBindingType, ColorTargetState, ColorWrites, CompareFunction,
+            binding_types::storage_buffer_read_only_sized, BindGroup, BindGroupEntries,
+            BindGroupLayout, BindGroupLayoutEntries, ColorTargetState, ColorWrites, CompareFunction,
             DepthStencilState, FragmentState, MultisampleState, PipelineCache, PrimitiveState,
-            RenderPipelineDescriptor, ShaderStages, SpecializedRenderPipeline,
+            RenderPipelineDescriptor, ShaderDefVal, ShaderStages, SpecializedRenderPipeline,
             SpecializedRenderPipelines, TextureFormat, VertexState,
         },
         renderer::RenderDevice,
+        settings::WgpuFeatures,
         sync_world::MainEntity,
-        view::{ExtractedView, ViewUniform},
+        view::{ExtractedView, ViewTarget},
     },
 };
 
-use super::{buffers::{PulledCubesBufferArrays, PulledCubesBuffers}, PulledCube};
+use super::{buffers::{ChunkDrawArgs, ChunkStorage, PulledCubesBufferArrays, PulledCubesBuffers}, PulledCube};
 
 pub(crate) type DrawPulledCubesPrepassCommands = (
     SetItemPipeline,
     SetPrepassViewBindGroup<0>,
-    DrawPulledCubesShadowPhaseItem,
+    DrawPulledCubesPrepassPhaseItem,
 );
 
 pub(crate) type DrawPulledCubesCommands = (
@@ -50,50 +58,81 @@ pub struct CubePullingPipeline {
     pub(crate) shader: Handle<Shader>,
     pub(crate) layout: BindGroupLayout,
     pub(crate) bind_group: BindGroup,
+    pub(crate) chunks_layout: BindGroupLayout,
+    pub(crate) chunks_bind_group: BindGroup,
     pub(crate) mesh_pipeline: MeshPipeline,
+    pub(crate) chunk_storage: ChunkStorage,
+    pub(crate) draw_mode: ChunkDrawMode,
+}
+
+/// How chunk draws are issued, picked from the render device's features
+/// when the renderer plugin finishes building.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkDrawMode {
+    /// All chunk draws of a pass in one `multi_draw_indirect`.
+    MultiDrawIndirect,
+    /// One `draw_indirect` per chunk.
+    DrawIndirect,
+    /// One direct draw per chunk, from the CPU copy of the arguments, for
+    /// devices that ignore `first_instance` in indirect draws.
+    Direct,
+}
+
+impl ChunkDrawMode {
+    pub fn for_device(render_device: &RenderDevice) -> Self {
+        let features = render_device.features();
+        if !features.contains(WgpuFeatures::INDIRECT_FIRST_INSTANCE) {
+            Self::Direct
+        } else if features.contains(WgpuFeatures::MULTI_DRAW_INDIRECT) {
+            Self::MultiDrawIndirect
+        } else {
+            Self::DrawIndirect
+        }
+    }
 }
 
 impl SpecializedRenderPipeline for CubePullingPipeline {
     type Key = MeshPipelineKey;
 
     fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
-        // let layout = &mesh_pipeline.get_view_layout(
-        //     MeshPipelineViewLayoutKey::from(msaa)
-        // );
+        let format = if key.contains(MeshPipelineKey::HDR) {
+            ViewTarget::TEXTURE_FORMAT_HDR
+        } else {
+            TextureFormat::bevy_default()
+        };
 
         RenderPipelineDescriptor {
             label: Some("custom render pipeline".into()),
             layout: vec![
                 self.mesh_pipeline.get_view_layout(key.into()).clone(),
                 self.layout.clone(),
+                self.chunks_layout.clone(),
             ],
             push_constant_ranges: vec![],
             vertex: VertexState {
                 shader: self.shader.clone(),
-                shader_defs: vec![],
+                shader_defs: chunk_shader_defs(self.chunk_storage),
                 entry_point: "vertex".into(),
                 buffers: vec![],
             },
             fragment: Some(FragmentState {
                 shader: self.shader.clone(),
-                shader_defs: vec!["SHADOW_FILTER_METHOD_GAUSSIAN".into()],
+                shader_defs: [chunk_shader_defs(self.chunk_storage), view_shader_defs(key)].concat(),
                 entry_point: "fragment".into(),
                 targets: vec![Some(ColorTargetState {
-                    // Ordinarily, you'd want to check whether the view has the
-                    // HDR format and substitute the appropriate texture format
-                    // here, but we omit that for simplicity.
-                    format: TextureFormat::bevy_default(),
+                    format,
                     blend: None,
                     write_mask: ColorWrites::ALL,
                 })],
             }),
             primitive: PrimitiveState::default(),
             // Note that if your view has no depth buffer this will need to be
-            // changed.
+            // changed. Equal depth passes so voxels already written by the
+            // prepass are shaded.
             depth_stencil: Some(DepthStencilState {
                 format: CORE_3D_DEPTH_FORMAT,
                 depth_write_enabled: true,
-                depth_compare: CompareFunction::Greater,
+                depth_compare: CompareFunction::GreaterEqual,
                 stencil: default(),
                 bias: default(),
             }),
@@ -114,62 +153,203 @@ impl FromWorld for CubePullingPipeline {
 
         let render_device = world.resource::<RenderDevice>();
 
-        let buffers = world.resource::<PulledCubesBufferArrays>();
+        let buffers = world.resource::<PulledCubesBuffers>();
+
+        let layout = create_bind_group_layout(render_device);
+
+        let bind_group = create_bind_group(render_device, &layout, buffers);
 
-        let layout = buffers.chunks_layout.clone();
+        let arrays = world.resource::<PulledCubesBufferArrays>();
 
-        let bind_group = buffers.as_bind_group(render_device);
+        let chunks_layout = arrays.chunks_layout.clone();
+
+        let chunks_bind_group = arrays.as_bind_group(render_device);
 
         let mesh_pipeline = world.resource::<MeshPipeline>().clone();
 
+        let chunk_storage = arrays.storage;
+
+        let draw_mode = *world.resource::<ChunkDrawMode>();
+
         CubePullingPipeline {
             shader: asset_server.load("shaders/vertex_pulled_cubes.wgsl"),
             bind_group,
             layout,
+            chunks_layout,
+            chunks_bind_group,
             mesh_pipeline,
+            chunk_storage,
+            draw_mode,
         }
     }
 }
 
+/// Shader defs for the parts of `key` that describe the view, matching what
+/// Bevy's mesh pipeline sets for the same bits so the PBR lighting and post
+/// processing functions behave the same for voxels as for meshes.
+fn view_shader_defs(key: MeshPipelineKey) -> Vec<ShaderDefVal> {
+    let mut shader_defs: Vec<ShaderDefVal> = Vec::new();
+
+    if key.msaa_samples() > 1 {
+        shader_defs.push("MULTISAMPLED".into());
+    }
+
+    if key.contains(MeshPipelineKey::TONEMAP_IN_SHADER) {
+        shader_defs.push("TONEMAP_IN_SHADER".into());
+        shader_defs.push(ShaderDefVal::UInt(
+            "TONEMAPPING_LUT_TEXTURE_BINDING_INDEX".into(),
+            TONEMAPPING_LUT_TEXTURE_BINDING_INDEX,
+        ));
+        shader_defs.push(ShaderDefVal::UInt(
+            "TONEMAPPING_LUT_SAMPLER_BINDING_INDEX".into(),
+            TONEMAPPING_LUT_SAMPLER_BINDING_INDEX,
+        ));
+
+        let method = key.intersection(MeshPipelineKey::TONEMAP_METHOD_RESERVED_BITS);
+        let method = if method == MeshPipelineKey::TONEMAP_METHOD_NONE {
+            "TONEMAP_METHOD_NONE"
+        } else if method == MeshPipelineKey::TONEMAP_METHOD_REINHARD {
+            "TONEMAP_METHOD_REINHARD"
+        } else if method == MeshPipelineKey::TONEMAP_METHOD_REINHARD_LUMINANCE {
+            "TONEMAP_METHOD_REINHARD_LUMINANCE"
+        } else if method == MeshPipelineKey::TONEMAP_METHOD_ACES_FITTED {
+            "TONEMAP_METHOD_ACES_FITTED"
+        } else if method == MeshPipelineKey::TONEMAP_METHOD_AGX {
+            "TONEMAP_METHOD_AGX"
+        } else if method == MeshPipelineKey::TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM {
+            "TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM"
+        } else if method == MeshPipelineKey::TONEMAP_METHOD_BLENDER_FILMIC {
+            "TONEMAP_METHOD_BLENDER_FILMIC"
+        } else {
+            "TONEMAP_METHOD_TONY_MC_MAPFACE"
+        };
+        shader_defs.push(method.into());
+
+        // Debanding is applied together with tonemapping.
+        if key.contains(MeshPipelineKey::DEBAND_DITHER) {
+            shader_defs.push("DEBAND_DITHER".into());
+        }
+    }
+
+    let shadow_filter_method = key.intersection(MeshPipelineKey::SHADOW_FILTER_METHOD_RESERVED_BITS);
+    if shadow_filter_method == MeshPipelineKey::SHADOW_FILTER_METHOD_HARDWARE_2X2 {
+        shader_defs.push("SHADOW_FILTER_METHOD_HARDWARE_2X2".into());
+    } else if shadow_filter_method == MeshPipelineKey::SHADOW_FILTER_METHOD_GAUSSIAN {
+        shader_defs.push("SHADOW_FILTER_METHOD_GAUSSIAN".into());
+    } else if shadow_filter_method == MeshPipelineKey::SHADOW_FILTER_METHOD_TEMPORAL {
+        shader_defs.push("SHADOW_FILTER_METHOD_TEMPORAL".into());
+    }
+
+    let view_projection = key.intersection(MeshPipelineKey::VIEW_PROJECTION_RESERVED_BITS);
+    if view_projection == MeshPipelineKey::VIEW_PROJECTION_NONSTANDARD {
+        shader_defs.push("VIEW_PROJECTION_NONSTANDARD".into());
+    } else if view_projection == MeshPipelineKey::VIEW_PROJECTION_PERSPECTIVE {
+        shader_defs.push("VIEW_PROJECTION_PERSPECTIVE".into());
+    } else if view_projection == MeshPipelineKey::VIEW_PROJECTION_ORTHOGRAPHIC {
+        shader_defs.push("VIEW_PROJECTION_ORTHOGRAPHIC".into());
+    }
+
+    if key.contains(MeshPipelineKey::SCREEN_SPACE_AMBIENT_OCCLUSION) {
+        shader_defs.push("SCREEN_SPACE_AMBIENT_OCCLUSION".into());
+    }
+
+    if key.contains(MeshPipelineKey::DISTANCE_FOG) {
+        shader_defs.push("DISTANCE_FOG".into());
+    }
+
+    if key.contains(MeshPipelineKey::TEMPORAL_JITTER) {
+        shader_defs.push("TEMPORAL_JITTER".into());
+    }
+
+    shader_defs
+}
+
+/// Renders voxel depth into the shadow maps of directional, point and spot
+/// lights and into the depth, normal and motion vector prepass of cameras.
+/// Shares its instance bind group layouts with [`CubePullingPipeline`] and
+/// binds the prepass view layout at group 0.
 #[derive(Resource)]
-pub struct CubePullingShadowPipeline {
+pub struct CubePullingPrepassPipeline {
     pub(crate) shader: Handle<Shader>,
     pub(crate) layout: BindGroupLayout,
-    pub(crate) bind_group: BindGroup,
-    pub(crate) view_layout: BindGroupLayout,
+    pub(crate) chunks_layout: BindGroupLayout,
+    pub(crate) chunk_storage: ChunkStorage,
+    pub(crate) view_layout_motion_vectors: BindGroupLayout,
+    pub(crate) view_layout_no_motion_vectors: BindGroupLayout,
+    /// Whether directional shadow maps can use unclipped depth natively,
+    /// rather than emulating it in the fragment shader.
+    pub(crate) depth_clip_control_supported: bool,
 }
 
-impl SpecializedRenderPipeline for CubePullingShadowPipeline {
+impl SpecializedRenderPipeline for CubePullingPrepassPipeline {
     type Key = MeshPipelineKey;
 
     fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
-        // let layout = &mesh_pipeline.get_view_layout(
-        //     MeshPipelineViewLayoutKey::from(msaa)
-        // );
+        let mut shader_defs = chunk_shader_defs(self.chunk_storage);
+
+        let normal_prepass = key.contains(MeshPipelineKey::NORMAL_PREPASS);
+        let motion_vector_prepass = key.contains(MeshPipelineKey::MOTION_VECTOR_PREPASS);
+        if normal_prepass {
+            shader_defs.push("NORMAL_PREPASS".into());
+        }
+        if motion_vector_prepass {
+            shader_defs.push("MOTION_VECTOR_PREPASS".into());
+        }
+
+        // Geometry behind the near plane of a directional light's cascade
+        // still casts shadows into it, so its depth must not be clipped.
+        let unclipped_depth = key.contains(MeshPipelineKey::UNCLIPPED_DEPTH_ORTHO);
+        let emulate_unclipped_depth = unclipped_depth && !self.depth_clip_control_supported;
+        if emulate_unclipped_depth {
+            shader_defs.push("UNCLIPPED_DEPTH_ORTHO_EMULATION".into());
+        }
+
+        // Same attachments as the prepass node: normals in slot 0, motion
+        // vectors in slot 1, or none at all for depth-only passes.
+        let mut targets = prepass_target_descriptors(normal_prepass, motion_vector_prepass, false);
+        if targets.iter().all(Option::is_none) {
+            targets.clear();
+        }
+
+        let fragment_required = !targets.is_empty() || emulate_unclipped_depth;
+        if fragment_required {
+            shader_defs.push("PREPASS_FRAGMENT".into());
+        }
+
+        let view_layout = if motion_vector_prepass {
+            &self.view_layout_motion_vectors
+        } else {
+            &self.view_layout_no_motion_vectors
+        };
 
         RenderPipelineDescriptor {
-            label: Some("custom render pipeline".into()),
-            layout: vec![self.view_layout.clone(), self.layout.clone()],
+            label: Some("pulled cubes prepass pipeline".into()),
+            layout: vec![
+                view_layout.clone(),
+                self.layout.clone(),
+                self.chunks_layout.clone(),
+            ],
             push_constant_ranges: vec![],
             vertex: VertexState {
                 shader: self.shader.clone(),
-                shader_defs: vec![],
-                entry_point: "shadow_vertex".into(),
+                shader_defs: shader_defs.clone(),
+                entry_point: "prepass_vertex".into(),
                 buffers: vec![],
             },
-            fragment: Some(FragmentState {
+            fragment: fragment_required.then(|| FragmentState {
                 shader: self.shader.clone(),
-                shader_defs: vec![],
-                entry_point: "shadow_fragment".into(),
-                targets: vec![],
+                shader_defs,
+                entry_point: "prepass_fragment".into(),
+                targets,
             }),
-            primitive: PrimitiveState::default(),
-            // Note that if your view has no depth buffer this will need to be
-            // changed.
+            primitive: PrimitiveState {
+                unclipped_depth: unclipped_depth && self.depth_clip_control_supported,
+                ..default()
+            },
             depth_stencil: Some(DepthStencilState {
                 format: CORE_3D_DEPTH_FORMAT,
                 depth_write_enabled: true,
-                depth_compare: CompareFunction::Greater,
+                depth_compare: CompareFunction::GreaterEqual,
                 stencil: default(),
                 bias: default(),
             }),
@@ -183,133 +363,112 @@ impl SpecializedRenderPipeline for CubePullingShadowPipeline {
     }
 }
 
-impl FromWorld for CubePullingShadowPipeline {
+impl FromWorld for CubePullingPrepassPipeline {
     fn from_world(world: &mut World) -> Self {
-        // Load and compile the shader in the background.
-        let asset_server = world.resource::<AssetServer>();
-
-        let render_device = world.resource::<RenderDevice>();
-
-        let buffers = world.resource::<PulledCubesBuffers>();
-
-        let layout = render_device.create_bind_group_layout(
-            None,
-            &[BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::VERTEX,
-                ty: BindingType::Buffer {
-                    ty: BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
-        );
-
-        let bind_group =
-            create_bind_group(render_device, &layout, buffers.instances.buffer().unwrap());
-
-        let view_layout = render_device.create_bind_group_layout(
-            "prepass_view_layout_no_motion_vectors",
-            &BindGroupLayoutEntries::with_indices(
-                ShaderStages::VERTEX_FRAGMENT,
-                (
-                    (0, uniform_buffer::<ViewUniform>(true)),
-                    (1, uniform_buffer::<GlobalsUniform>(false)),
-                ),
-            ),
-        );
-
-        CubePullingShadowPipeline {
-            shader: asset_server.load("shaders/vertex_pulled_cubes.wgsl"),
-            layout,
-            bind_group,
-            view_layout,
+        let pipeline = world.resource::<CubePullingPipeline>();
+
+        // Prepass and shadow views are bound by `SetPrepassViewBindGroup`, so
+        // the layouts have to be the ones its bind groups were created with.
+        let prepass_pipeline = &world.resource::<PrepassPipeline<StandardMaterial>>().internal;
+
+        let depth_clip_control_supported = world
+            .resource::<RenderDevice>()
+            .features()
+            .contains(WgpuFeatures::DEPTH_CLIP_CONTROL);
+
+        CubePullingPrepassPipeline {
+            shader: pipeline.shader.clone(),
+            layout: pipeline.layout.clone(),
+            chunks_layout: pipeline.chunks_layout.clone(),
+            chunk_storage: pipeline.chunk_storage,
+            view_layout_motion_vectors: prepass_pipeline.view_layout_motion_vectors.clone(),
+            view_layout_no_motion_vectors: prepass_pipeline.view_layout_no_motion_vectors.clone(),
+            depth_clip_control_supported,
         }
     }
 }
 
 pub(crate) struct DrawPulledCubesPhaseItem;
 
-pub(crate) struct DrawPulledCubesShadowPhaseItem;
+pub(crate) struct DrawPulledCubesPrepassPhaseItem;
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn queue_custom_phase_item(
     pipeline_cache: Res<PipelineCache>,
     pulled_cube_pipeline: Res<CubePullingPipeline>,
-//    pulled_cube_shadow_pipeline: Res<CubePullingShadowPipeline>,
+    pulled_cube_prepass_pipeline: Res<CubePullingPrepassPipeline>,
     mut opaque_render_phases: ResMut<ViewBinnedRenderPhases<Opaque3d>>,
-//    mut shadow_render_phases: ResMut<ViewBinnedRenderPhases<Shadow>>,
+    mut opaque_prepass_render_phases: ResMut<ViewBinnedRenderPhases<Opaque3dPrepass>>,
+    mut shadow_render_phases: ResMut<ViewBinnedRenderPhases<Shadow>>,
     opaque_draw_functions: Res<DrawFunctions<Opaque3d>>,
-//    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
-    view_lights: Query<(Entity, &ViewLightEntities)>,
-    view_light_entities: Query<&LightEntity>,
+    opaque_prepass_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    view_lights: Query<&ViewLightEntities, With<ExtractedView>>,
+    view_light_entities: Query<(&LightEntity, &ExtractedView)>,
     mut specialized_render_pipelines: ResMut<SpecializedRenderPipelines<CubePullingPipeline>>,
-//    mut specialized_shadow_render_pipelines: ResMut<
-//        SpecializedRenderPipelines<CubePullingShadowPipeline>,
-//    >,
-    views: Query<(&ExtractedView, &Msaa)>,
-    _point_light_entities: Query<&RenderCubemapVisibleEntities, With<ExtractedPointLight>>,
-    directional_light_entities: Query<
-        &RenderCascadesVisibleEntities,
-        With<ExtractedDirectionalLight>,
+    mut specialized_prepass_render_pipelines: ResMut<
+        SpecializedRenderPipelines<CubePullingPrepassPipeline>,
     >,
-    _spot_light_entities: Query<&RenderVisibleMeshEntities, With<ExtractedPointLight>>,
+    view_key_cache: Res<ViewKeyCache>,
+    views: Query<(&ExtractedView, &Msaa)>,
     mut next_tick: Local<Tick>,
 ) {
     let draw_pulled_cubes_phase_item = opaque_draw_functions.read().id::<DrawPulledCubesCommands>();
 
-//    let pulled_cubes_prepass_phase_item = shadow_draw_functions
-//        .read()
-//        .id::<DrawPulledCubesPrepassCommands>();
+    let draw_pulled_cubes_opaque_prepass_phase_item = opaque_prepass_draw_functions
+        .read()
+        .id::<DrawPulledCubesPrepassCommands>();
 
-    for (entity, view_lights) in &view_lights {
+    let pulled_cubes_prepass_phase_item = shadow_draw_functions
+        .read()
+        .id::<DrawPulledCubesPrepassCommands>();
+
+    // Every cascade of a directional light and every face of a point light
+    // is a view of its own, with chunks culled against its frustum in
+    // `update_chunk_buffers`.
+    for view_lights in &view_lights {
         for view_light_entity in view_lights.lights.iter().copied() {
-            let Ok(light_entity) = view_light_entities.get(view_light_entity) else {
+            let Ok((light_entity, extracted_view_light)) =
+                view_light_entities.get(view_light_entity)
+            else {
                 continue;
             };
 
-//            let Some(shadow_phase) = shadow_render_phases.get_mut(&view_light_entity) else {
-//                continue;
-//            };
+            let Some(shadow_phase) =
+                shadow_render_phases.get_mut(&extracted_view_light.retained_view_entity)
+            else {
+                continue;
+            };
 
             let is_directional_light = matches!(light_entity, LightEntity::Directional { .. });
             let mut light_key = MeshPipelineKey::DEPTH_PREPASS;
-            light_key.set(MeshPipelineKey::NONE, is_directional_light);
-
-            // NOTE: Lights with shadow mapping disabled will have no visible entities
-            // so no meshes will be queued
-
-            if let LightEntity::Directional {
-                light_entity,
-                cascade_index,
-            } = light_entity
-            {
-                directional_light_entities
-                    .get(*light_entity)
-                    .expect("Failed to get directional light visible entities")
-                    .entities
-                    .get(&entity)
-                    .expect("Failed to get directional light visible entities for view")
-                    .get(*cascade_index)
-                    .expect("Failed to get directional light visible entities for cascade");
-            }
-
-//            let pipeline_id = specialized_shadow_render_pipelines.specialize(
-//                &pipeline_cache,
-//                &pulled_cube_shadow_pipeline,
-//                MeshPipelineKey::NONE,
-//            );
-
-//            shadow_phase.add(
-//                ShadowBinKey {
-//                    pipeline: pipeline_id,
-//                    draw_function: pulled_cubes_prepass_phase_item,
-//                    asset_id: AssetId::<Mesh>::invalid().untyped(),
-//                },
-//                (Entity::PLACEHOLDER, MainEntity::from(Entity::PLACEHOLDER)),
-//                BinnedRenderPhaseType::NonMesh,
-//            );
+            light_key.set(MeshPipelineKey::UNCLIPPED_DEPTH_ORTHO, is_directional_light);
+
+            let pipeline_id = specialized_prepass_render_pipelines.specialize(
+                &pipeline_cache,
+                &pulled_cube_prepass_pipeline,
+                light_key,
+            );
+
+            let this_tick = next_tick.get() + 1;
+            next_tick.set(this_tick);
+
+            shadow_phase.add(
+                ShadowBatchSetKey {
+                    pipeline: pipeline_id,
+                    draw_function: pulled_cubes_prepass_phase_item,
+                    material_bind_group_index: None,
+                    vertex_slab: default(),
+                    index_slab: None,
+                },
+                ShadowBinKey {
+                    asset_id: AssetId::<Mesh>::invalid().untyped(),
+                },
+                (Entity::PLACEHOLDER, MainEntity::from(Entity::PLACEHOLDER)),
+                InputUniformIndex::default(),
+                BinnedRenderPhaseType::NonMesh,
+                *next_tick,
+            );
         }
     }
 
@@ -322,13 +481,16 @@ pub(crate) fn queue_custom_phase_item(
         };
 
 
-        // Find all the custom rendered entities that are visible from this
-        // view.
-        // Ordinarily, the [`SpecializedRenderPipeline::Key`] would contain
-        // some per-view settings, such as whether the view is HDR, but for
-        // simplicity's sake we simply hard-code the view's characteristics,
-        // with the exception of number of MSAA samples.
-        let view_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+        // Bevy computes the key of every view for its own mesh pipelines,
+        // covering HDR, tonemapping, debanding, the shadow filter method,
+        // MSAA and the prepasses it has.
+        let view_key = view_key_cache
+            .get(&view.retained_view_entity)
+            .copied()
+            .unwrap_or_else(|| {
+                MeshPipelineKey::from_msaa_samples(msaa.samples())
+                    | MeshPipelineKey::from_hdr(view.hdr)
+            });
 
         let pipeline_id = specialized_render_pipelines.specialize(
             &pipeline_cache,
@@ -365,25 +527,73 @@ pub(crate) fn queue_custom_phase_item(
             BinnedRenderPhaseType::NonMesh,
             *next_tick,
         );
+
+        // Voxels are fully opaque, so they only ever go into the opaque
+        // prepass, never the alpha mask one.
+        let Some(opaque_prepass_phase) =
+            opaque_prepass_render_phases.get_mut(&view.retained_view_entity)
+        else {
+            continue;
+        };
+
+        let prepass_key = MeshPipelineKey::from_msaa_samples(msaa.samples())
+            | MeshPipelineKey::DEPTH_PREPASS
+            | view_key.intersection(
+                MeshPipelineKey::NORMAL_PREPASS | MeshPipelineKey::MOTION_VECTOR_PREPASS,
+            );
+
+        let prepass_pipeline_id = specialized_prepass_render_pipelines.specialize(
+            &pipeline_cache,
+            &pulled_cube_prepass_pipeline,
+            prepass_key,
+        );
+
+        opaque_prepass_phase.add(
+            OpaqueNoLightmap3dBatchSetKey {
+                pipeline: prepass_pipeline_id,
+                draw_function: draw_pulled_cubes_opaque_prepass_phase_item,
+                material_bind_group_index: None,
+                vertex_slab: default(),
+                index_slab: None,
+            },
+            OpaqueNoLightmap3dBinKey {
+                asset_id: AssetId::<Mesh>::invalid().untyped(),
+            },
+            (Entity::PLACEHOLDER, MainEntity::from(Entity::PLACEHOLDER)),
+            InputUniformIndex::default(),
+            BinnedRenderPhaseType::NonMesh,
+            *next_tick,
+        );
     }
 }
 
+/// Layout of the instance data bind group: free-standing cubes and block
+/// materials. Chunk faces are bound separately by [`PulledCubesBufferArrays`].
+pub(crate) fn create_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(
+        "cube_instance_data_layout",
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::VERTEX,
+            (
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer_read_only_sized(false, None),
+            ),
+        ),
+    )
+}
+
 pub(crate) fn create_bind_group(
     render_device: &RenderDevice,
     layout: &BindGroupLayout,
-    buffer: &Buffer,
+    buffers: &PulledCubesBuffers,
 ) -> BindGroup {
     render_device.create_bind_group(
         "cube_instance_data",
         layout,
-        &[BindGroupEntry {
-            binding: 0,
-            resource: BindingResource::Buffer(BufferBinding {
-                buffer,
-                offset: 0,
-                size: None,
-            }),
-        }],
+        &BindGroupEntries::sequential((
+            buffers.instances.buffer().unwrap().as_entire_binding(),
+            buffers.materials.buffer().unwrap().as_entire_binding(),
+        )),
     )
 }
 
@@ -391,7 +601,11 @@ impl<P> RenderCommand<P> for DrawPulledCubesPhaseItem
 where
     P: PhaseItem,
 {
-    type Param = (SRes<PulledCubesBuffers>, SRes<CubePullingPipeline>);
+    type Param = (
+        SRes<PulledCubesBuffers>,
+        SRes<PulledCubesBufferArrays>,
+        SRes<CubePullingPipeline>,
+    );
 
     type ViewQuery = ();
 
@@ -405,53 +619,124 @@ where
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         // Borrow check workaround.
-        let custom_phase_item_buffers = resources.0.into_inner();
-        let pipeline = resources.1.into_inner();
-
-        pass.set_bind_group(1, &pipeline.bind_group, &[]);
+        let buffers = resources.0.into_inner();
+        let arrays = resources.1.into_inner();
+        let pipeline = resources.2.into_inner();
 
-        pass.draw(
-            0..(3 * 2 * 3), 0..1
-        );
-        //pass.draw(
-        //    0..(custom_phase_item_buffers.instances.len() as u32 * VERTICES_PER_CUBE),
-        //    0..1,
-        //);
+        draw_pulled_cubes(pass, buffers, arrays, pipeline, arrays.main_draws.clone());
 
         RenderCommandResult::Success
     }
 }
 
+/// Draws the free-standing cubes and the chunk draws in `chunk_draws` of
+/// the indirect argument buffer. Shared by the main and shadow passes,
+/// which only differ in the view bound at group 0.
+fn draw_pulled_cubes<'w>(
+    pass: &mut TrackedRenderPass<'w>,
+    buffers: &'w PulledCubesBuffers,
+    arrays: &'w PulledCubesBufferArrays,
+    pipeline: &'w CubePullingPipeline,
+    chunk_draws: Range<u32>,
+) {
+    pass.set_bind_group(1, &pipeline.bind_group, &[]);
+    pass.set_bind_group(2, &pipeline.chunks_bind_group, &[]);
+
+    let cube_count = buffers.instances.len() as u32;
+    if cube_count > 0 {
+        pass.draw(0..(cube_count * VERTICES_PER_CUBE), 0..1);
+    }
+
+    // Chunk faces share the pipeline, the shader tells them apart from
+    // cubes by the instance index, which also selects the chunk slot.
+    let Some(draw_args) = arrays.chunk_draw_args.buffer() else {
+        return;
+    };
+    if chunk_draws.is_empty() {
+        return;
+    }
+
+    let stride = size_of::<ChunkDrawArgs>() as u64;
+    match pipeline.draw_mode {
+        ChunkDrawMode::MultiDrawIndirect => {
+            pass.multi_draw_indirect(draw_args, chunk_draws.start as u64 * stride, chunk_draws.len() as u32);
+        }
+        ChunkDrawMode::DrawIndirect => {
+            for i in chunk_draws {
+                pass.draw_indirect(draw_args, i as u64 * stride);
+            }
+        }
+        ChunkDrawMode::Direct => {
+            let values = arrays.chunk_draw_args.values();
+            for args in &values[chunk_draws.start as usize..chunk_draws.end as usize] {
+                pass.draw(
+                    args.first_vertex..args.first_vertex + args.vertex_count,
+                    args.first_instance..args.first_instance + args.instance_count,
+                );
+            }
+        }
+    }
+}
+
+/// Vertices pulled per cube: two triangles for each of the three faces
+/// pointing towards the camera.
 const VERTICES_PER_CUBE : u32 = 18;
 
-impl<P> RenderCommand<P> for DrawPulledCubesShadowPhaseItem
+pub(crate) const VERTICES_PER_FACE : u32 = 6;
+
+/// Draws with an instance index of at least this pull chunk faces instead of
+/// cubes.
+pub(crate) const FIRST_CHUNK_INSTANCE : u32 = 1;
+
+/// Passes the vertex counts above and the chunk face layout to
+/// `vertex_pulled_cubes.wgsl`, so the shader always decodes vertex indices
+/// the same way the draws issue them.
+fn chunk_shader_defs(storage: ChunkStorage) -> Vec<ShaderDefVal> {
+    let mut shader_defs = vec![
+        ShaderDefVal::UInt("VERTICES_PER_CUBE".into(), VERTICES_PER_CUBE),
+        ShaderDefVal::UInt("VERTICES_PER_FACE".into(), VERTICES_PER_FACE),
+        ShaderDefVal::UInt("FIRST_CHUNK_INSTANCE".into(), FIRST_CHUNK_INSTANCE),
+    ];
+    if storage == ChunkStorage::BindingArray {
+        shader_defs.push("CHUNK_BINDING_ARRAY".into());
+    }
+    shader_defs
+}
+
+impl<P> RenderCommand<P> for DrawPulledCubesPrepassPhaseItem
 where
     P: PhaseItem,
 {
-    type Param = (SRes<PulledCubesBuffers>, SRes<CubePullingPipeline>);
+    type Param = (
+        SRes<PulledCubesBuffers>,
+        SRes<PulledCubesBufferArrays>,
+        SRes<CubePullingPipeline>,
+    );
 
-    type ViewQuery = ();
+    type ViewQuery = (Entity, Has<LightEntity>);
 
     type ItemQuery = ();
 
     fn render<'w>(
         _: &P,
-        _: ROQueryItem<'w, Self::ViewQuery>,
+        (view, is_shadow_view): ROQueryItem<'w, Self::ViewQuery>,
         _: Option<ROQueryItem<'w, Self::ItemQuery>>,
         resources: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         // Borrow check workaround.
-        let custom_phase_item_buffers = resources.0.into_inner();
-        let pipeline = resources.1.into_inner();
-
-        pass.set_bind_group(1, &pipeline.bind_group, &[]);
-
-        // Draw one triangle (3 vertices).
-        pass.draw(
-            0..(custom_phase_item_buffers.instances.len() as u32 * VERTICES_PER_CUBE),
-            0..1,
-        );
+        let buffers = resources.0.into_inner();
+        let arrays = resources.1.into_inner();
+        let pipeline = resources.2.into_inner();
+
+        // Shadow views draw the chunks inside their own frustum, camera
+        // prepasses the same chunks as the main pass.
+        let chunk_draws = if is_shadow_view {
+            arrays.shadow_draws.get(&view).cloned().unwrap_or_default()
+        } else {
+            arrays.main_draws.clone()
+        };
+        draw_pulled_cubes(pass, buffers, arrays, pipeline, chunk_draws);
 
         RenderCommandResult::Success
     }
@@ -1,13 +1,160 @@
-use crate::render::buffers::{Cube, ToCubes};
+use std::{borrow::Cow, collections::HashMap};
 
-pub type BlockState = bool;
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
 
-impl ToCubes for bool {
-    fn to_cubes(&self) -> Vec<Cube> {
-        if *self {
-            vec![Cube::default()]
-        } else {
-            vec![]
+/// Compact id of a block type registered in the [`BlockRegistry`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockState(pub u16);
+
+impl BlockState {
+    pub const AIR: Self = Self(0);
+    pub const STONE: Self = Self(1);
+    pub const GRASS: Self = Self(2);
+    pub const DIRT: Self = Self(3);
+    pub const SAND: Self = Self(4);
+    pub const SNOW: Self = Self(5);
+    pub const LOG: Self = Self(6);
+    pub const LEAVES: Self = Self(7);
+
+    pub fn id(&self) -> u16 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CollisionShape {
+    #[default]
+    None,
+    Full,
+    /// Axis-aligned box in block-local coordinates, within `0.0..=1.0`.
+    Box { min: Vec3, max: Vec3 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BlockMaterial {
+    /// Not rendered at all.
+    #[default]
+    None,
+    Color(Color),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub name: Cow<'static, str>,
+    pub solid: bool,
+    pub opaque: bool,
+    pub light_emission: u8,
+    pub collision: CollisionShape,
+    pub material: BlockMaterial,
+}
+
+impl Block {
+    /// A solid, opaque, full cube rendered with `color`.
+    pub fn solid(name: impl Into<Cow<'static, str>>, color: Color) -> Self {
+        Self {
+            name: name.into(),
+            solid: true,
+            opaque: true,
+            light_emission: 0,
+            collision: CollisionShape::Full,
+            material: BlockMaterial::Color(color),
         }
     }
+
+    /// An empty, transparent block without collision.
+    pub fn empty(name: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            name: name.into(),
+            solid: false,
+            opaque: false,
+            light_emission: 0,
+            collision: CollisionShape::None,
+            material: BlockMaterial::None,
+        }
+    }
+
+    pub fn with_light_emission(mut self, light_emission: u8) -> Self {
+        self.light_emission = light_emission;
+        self
+    }
+
+    pub fn with_collision(mut self, collision: CollisionShape) -> Self {
+        self.collision = collision;
+        self
+    }
+
+    pub fn is_rendered(&self) -> bool {
+        !matches!(self.material, BlockMaterial::None)
+    }
+}
+
+/// Every block type known to the world, indexed by [`BlockState`].
+///
+/// `air`, `stone`, `grass`, `dirt`, `sand`, `snow`, `log` and `leaves` are
+/// always registered first so built-in generators can refer to them through
+/// the [`BlockState`] constants.
+#[derive(Debug, Clone, Resource, ExtractResource)]
+pub struct BlockRegistry {
+    blocks: Vec<Block>,
+    by_name: HashMap<Cow<'static, str>, BlockState>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            blocks: Vec::new(),
+            by_name: HashMap::new(),
+        };
+        registry.register(Block::empty("air"));
+        registry.register(Block::solid("stone", Color::srgb(0.5, 0.5, 1.0)));
+        registry.register(Block::solid("grass", Color::srgb(0.3, 0.6, 0.2)));
+        registry.register(Block::solid("dirt", Color::srgb(0.45, 0.3, 0.2)));
+        registry.register(Block::solid("sand", Color::srgb(0.9, 0.85, 0.6)));
+        registry.register(Block::solid("snow", Color::srgb(0.95, 0.95, 1.0)));
+        registry.register(Block::solid("log", Color::srgb(0.4, 0.27, 0.15)));
+        registry.register(Block::solid("leaves", Color::srgb(0.2, 0.45, 0.15)));
+        registry
+    }
+}
+
+impl BlockRegistry {
+    /// Registers a new block type and returns its id.
+    ///
+    /// Panics if a block with the same name already exists or the id space
+    /// is exhausted.
+    pub fn register(&mut self, block: Block) -> BlockState {
+        assert!(
+            !self.by_name.contains_key(&block.name),
+            "block `{}` is already registered",
+            block.name
+        );
+        let id = u16::try_from(self.blocks.len()).expect("too many block types registered");
+        let state = BlockState(id);
+        self.by_name.insert(block.name.clone(), state);
+        self.blocks.push(block);
+        state
+    }
+
+    pub fn get(&self, state: BlockState) -> Option<&Block> {
+        self.blocks.get(state.0 as usize)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<BlockState> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BlockState, &Block)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(|(id, block)| (BlockState(id as u16), block))
+    }
 }
@@ -1,31 +1,135 @@
-use std::{
-    ops::{Index, IndexMut},
-    slice::{Iter, IterMut},
+use std::ops::Index;
+
+use bevy::{
+    math::I64Vec3,
+    prelude::*,
+    render::{
+        primitives::Aabb,
+        sync_world::{RenderEntity, SyncToRenderWorld},
+        view::{self, VisibilityClass},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
 };
 
-use bevy::{math::I64Vec3, prelude::*, render::{extract_component::{ExtractComponent, ExtractComponentPlugin}, Render, RenderApp}};
+use crate::render::{
+    buffers::{self, update_buffers, write_buffers, PackedFace},
+    mesher::{cull_faces, greedy_quads, ChunkNeighbours, MeshingMode, Quad},
+};
+
+use super::{
+    block::{BlockRegistry, BlockState},
+    generation::WorldGenerator,
+    palette::{Iter, PalettedContainer},
+    finish_chunk_generation, propagate_chunk_offsets,
+    streaming::{stream_chunks, ChunkStreamingBudget},
+    ChunkOffset,
+};
+
+/// A chunk size to simulate and, with
+/// [`VoxelRendererPlugin`](crate::render::VoxelRendererPlugin), render.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkSize {
+    size: usize,
+    /// How chunks of this size are meshed once rendered.
+    pub meshing: MeshingMode,
+    simulate: fn(&mut App),
+    render: fn(&mut App, MeshingMode),
+}
+
+impl ChunkSize {
+    pub fn new<const SIZE: usize>() -> Self {
+        Self {
+            size: SIZE,
+            meshing: MeshingMode::default(),
+            simulate: |app| {
+                app.add_plugins(ChunkPlugin::<SIZE>);
+            },
+            render: |app, meshing| {
+                app.add_plugins(ChunkRenderPlugin::<SIZE>::with_meshing(meshing));
+            },
+        }
+    }
 
-use crate::render::buffers::{self, update_buffers, write_buffers, Cube, ToCubes};
+    pub fn with_meshing(mut self, meshing: MeshingMode) -> Self {
+        self.meshing = meshing;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Adds [`ChunkPlugin`] for this size.
+    pub fn simulate(&self, app: &mut App) {
+        (self.simulate)(app);
+    }
+
+    /// Adds [`ChunkRenderPlugin`] for this size, meshed with
+    /// [`meshing`](Self::meshing).
+    pub fn render(&self, app: &mut App) {
+        (self.render)(app, self.meshing);
+    }
+}
 
-use super::{block::BlockState, generation::BlockGenerator, propagate_chunk_offsets};
+/// Chunk sizes simulated by [`VoxelPlugin`](crate::VoxelPlugin), so the
+/// renderer follows the same ones.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ChunkSizes(pub Vec<ChunkSize>);
 
+/// Simulates chunks of `SIZE`, without rendering them.
 #[derive(Default)]
 pub struct ChunkPlugin<const SIZE: usize>;
 
 impl <const SIZE: usize> Plugin for ChunkPlugin<SIZE> {
     fn build(&self, app: &mut App) {
-        app.add_plugins(ExtractComponentPlugin::<Chunk<SIZE>>::default());
-        app.add_systems(PostUpdate, propagate_chunk_offsets::<SIZE>);
-        app.get_sub_app_mut(RenderApp)
-            .unwrap()
+        app.add_systems(
+            PostUpdate,
+            propagate_chunk_offsets::<SIZE>.before(TransformSystem::TransformPropagate),
+        )
+        .add_systems(Update, (stream_chunks::<SIZE>, finish_chunk_generation::<SIZE>))
+        .init_resource::<ChunkStreamingBudget>();
+    }
+}
+
+/// Extracts and meshes chunks of `SIZE` for rendering.
+#[derive(Default)]
+pub struct ChunkRenderPlugin<const SIZE: usize> {
+    pub meshing: MeshingMode,
+}
+
+impl<const SIZE: usize> ChunkRenderPlugin<SIZE> {
+    pub fn with_meshing(meshing: MeshingMode) -> Self {
+        Self { meshing }
+    }
+}
+
+/// Render world copy of [`ChunkRenderPlugin::meshing`] for chunks of `SIZE`.
+#[derive(Resource, Clone, Copy)]
+pub struct ChunkMeshing<const SIZE: usize>(pub MeshingMode);
+
+impl <const SIZE: usize> Plugin for ChunkRenderPlugin<SIZE> {
+    fn build(&self, app: &mut App) {
+        assert!(
+            SIZE <= PackedFace::MAX_CHUNK_SIZE,
+            "chunks larger than {} blocks cannot be rendered",
+            PackedFace::MAX_CHUNK_SIZE
+        );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .insert_resource(ChunkMeshing::<SIZE>(self.meshing))
+            .add_systems(ExtractSchedule, extract_chunks::<SIZE>)
             .add_systems(Render, buffers::update_chunk_buffers::<SIZE>
+                .in_set(RenderSet::PrepareResources)
                 .after(update_buffers)
                 .before(write_buffers)
             );
     }
 }
 
-const fn index_to_pos(index: usize, size: usize) -> IVec3 {
+pub(crate) const fn index_to_pos(index: usize, size: usize) -> IVec3 {
     IVec3::new(
         (index / (size * size)) as i32,
         ((index / size) % size) as i32,
@@ -33,30 +137,58 @@ const fn index_to_pos(index: usize, size: usize) -> IVec3 {
     )
 }
 
+const fn pos_to_index(pos: IVec3, size: usize) -> usize {
+    pos.x as usize * size * size + pos.y as usize * size + pos.z as usize
+}
+
+/// A `SIZE`³ block of the world. Its [`Aabb`] is derived from `SIZE`, so
+/// chunks take part in frustum culling without further setup.
 #[derive(Debug, Component)]
-#[require(Transform, Visibility)]
+#[require(Transform, Visibility, VisibilityClass, SyncToRenderWorld, Aabb = Self::aabb())]
+#[component(on_add = view::add_visibility_class::<Self>)]
 pub struct Chunk<const SIZE: usize> {
-    pub blocks: [[[BlockState; SIZE]; SIZE]; SIZE],
+    pub blocks: PalettedContainer<BlockState>,
 }
 
 impl<const SIZE: usize> Chunk<SIZE> {
-    fn to_extracted(&self) -> ExtractedChunk<SIZE> {
+    fn to_extracted(&self, offset: Option<&ChunkOffset>, level: Option<&ChildOf>) -> ExtractedChunk<SIZE> {
         ExtractedChunk {
             blocks: self.blocks.clone(),
+            offset: offset.map(|offset| offset.0),
+            level: level.map(ChildOf::parent),
         }
     }
 
-    pub fn generate(generator: BlockGenerator, offset: I64Vec3) -> Self {
-        let mut output = Self::default();
+    pub fn generate(generator: &dyn WorldGenerator, offset: I64Vec3) -> Self {
+        let mut blocks = vec![BlockState::AIR; Self::volume()];
+        generator.generate_chunk(offset, SIZE, &mut blocks);
 
-        for i in 0..Self::volume() {
-            let pos = offset + Self::index_to_pos(i).as_i64vec3();
-            output[i] = generator(pos);
+        let mut output = Self::default();
+        for (i, block) in blocks.into_iter().enumerate() {
+            output.set(i, block);
         }
 
         output
     }
 
+    /// Writes `state` at `index`, returning the state previously stored there.
+    pub fn set(&mut self, index: usize, state: BlockState) -> BlockState {
+        self.blocks.set(index, state)
+    }
+
+    pub fn set_at(&mut self, pos: IVec3, state: BlockState) -> BlockState {
+        self.set(pos_to_index(pos, SIZE), state)
+    }
+
+    pub fn fill(&mut self, state: BlockState) {
+        self.blocks.fill(state);
+    }
+
+    /// Returns the block filling the whole chunk, if it is uniform.
+    pub fn as_uniform(&self) -> Option<BlockState> {
+        self.blocks.as_uniform().copied()
+    }
+
     pub fn volume() -> usize {
         SIZE*SIZE*SIZE
     }
@@ -64,12 +196,19 @@ impl<const SIZE: usize> Chunk<SIZE> {
     pub fn index_to_pos(index: usize) -> IVec3 {
         index_to_pos(index, SIZE)
     }
+
+    /// Bounds of the chunk's geometry in local space. Blocks are centered on
+    /// integer positions, so the chunk spans `-0.5..SIZE - 0.5`.
+    pub fn aabb() -> Aabb {
+        let size = SIZE as f32;
+        Aabb::from_min_max(Vec3::splat(-0.5), Vec3::splat(size - 0.5))
+    }
 }
 
 impl<const SIZE: usize> Default for Chunk<SIZE> {
     fn default() -> Self {
         Self {
-            blocks: [[[false; SIZE]; SIZE]; SIZE],
+            blocks: PalettedContainer::new(SIZE * SIZE * SIZE, BlockState::default()),
         }
     }
 }
@@ -79,46 +218,23 @@ impl<'a, const SIZE: usize> IntoIterator for &'a Chunk<SIZE> {
     type IntoIter = Iter<'a, BlockState>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.blocks.as_flattened().as_flattened().iter()
+        self.blocks.iter()
     }
 }
 
-impl<'a, const SIZE: usize> IntoIterator for &'a mut Chunk<SIZE> {
-    type Item = &'a mut BlockState;
-    type IntoIter = IterMut<'a, BlockState>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        self.blocks.as_flattened_mut().as_flattened_mut().iter_mut()
-    }
-}
-
-impl<'a, const SIZE: usize> Index<usize> for Chunk<SIZE> {
+impl<const SIZE: usize> Index<usize> for Chunk<SIZE> {
     type Output = BlockState;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.blocks.as_flattened().as_flattened()[index]
+        self.blocks.get(index)
     }
 }
 
-impl<'a, const SIZE: usize> IndexMut<usize> for Chunk<SIZE> {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.blocks.as_flattened_mut().as_flattened_mut()[index]
-    }
-}
-
-impl<'a, const SIZE: usize> Index<IVec3> for Chunk<SIZE> {
+impl<const SIZE: usize> Index<IVec3> for Chunk<SIZE> {
     type Output = BlockState;
 
     fn index(&self, index: IVec3) -> &Self::Output {
-        let index = index.x as usize * SIZE * SIZE + index.y as usize * SIZE + index.z as usize;
-        &self.blocks.as_flattened().as_flattened()[index]
-    }
-}
-
-impl<'a, const SIZE: usize> IndexMut<IVec3> for Chunk<SIZE> {
-    fn index_mut(&mut self, index: IVec3) -> &mut Self::Output {
-        let index = index.x as usize * SIZE * SIZE + index.y as usize * SIZE + index.z as usize;
-        &mut self.blocks.as_flattened_mut().as_flattened_mut()[index]
+        self.blocks.get(pos_to_index(index, SIZE))
     }
 }
 
@@ -127,20 +243,42 @@ pub type Chunk32 = Chunk<32>;
 
 #[derive(Debug, Component)]
 pub struct ExtractedChunk<const SIZE: usize> {
-    pub blocks: [[[BlockState; SIZE]; SIZE]; SIZE],
+    pub blocks: PalettedContainer<BlockState>,
+    /// Chunk coordinate, used to find neighbouring chunks when meshing.
+    pub offset: Option<I64Vec3>,
+    /// Main world entity of the level the chunk belongs to, so only chunks
+    /// of the same level are neighbours.
+    pub level: Option<Entity>,
 }
 
-impl<const SIZE: usize> ExtractComponent for Chunk<SIZE> {
-    type QueryData = (&'static Self, &'static Transform, &'static ViewVisibility);
-    type QueryFilter = ();
-    type Out = (ExtractedChunk<SIZE>, Transform, ViewVisibility);
-
-    fn extract_component(
-        item: bevy::ecs::query::QueryItem<'_, Self::QueryData>,
-    ) -> Option<Self::Out> {
-        let (chunk, transform, visibility) = item;
-        Some((chunk.to_extracted(), *transform, *visibility))
+type ExtractedChunkQuery<'a, const SIZE: usize> = (
+    RenderEntity,
+    Ref<'a, Chunk<SIZE>>,
+    Option<&'a ChunkOffset>,
+    Option<&'a ChildOf>,
+    &'a GlobalTransform,
+    &'a ViewVisibility,
+);
+
+/// Extracts chunk transforms and visibility every frame, but only clones the
+/// block data of chunks that changed since the last extraction. The global
+/// transform is extracted so chunks follow their level.
+pub(crate) fn extract_chunks<const SIZE: usize>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    chunks: Extract<Query<ExtractedChunkQuery<SIZE>>>,
+) {
+    let mut changed = Vec::new();
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, chunk, offset, level, transform, visibility) in &chunks {
+        if chunk.is_changed() {
+            changed.push((entity, chunk.to_extracted(offset, level)));
+        }
+        values.push((entity, (*transform, *visibility)));
     }
+    *previous_len = values.len();
+    commands.try_insert_batch(changed);
+    commands.try_insert_batch(values);
 }
 
 impl<'a, const SIZE: usize> IntoIterator for &'a ExtractedChunk<SIZE> {
@@ -148,68 +286,44 @@ impl<'a, const SIZE: usize> IntoIterator for &'a ExtractedChunk<SIZE> {
     type IntoIter = Iter<'a, BlockState>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.blocks.as_flattened().as_flattened().iter()
-    }
-}
-
-impl<'a, const SIZE: usize> IntoIterator for &'a mut ExtractedChunk<SIZE> {
-    type Item = &'a mut BlockState;
-    type IntoIter = IterMut<'a, BlockState>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        self.blocks.as_flattened_mut().as_flattened_mut().iter_mut()
+        self.blocks.iter()
     }
 }
 
-impl<'a, const SIZE: usize> Index<usize> for ExtractedChunk<SIZE> {
+impl<const SIZE: usize> Index<usize> for ExtractedChunk<SIZE> {
     type Output = BlockState;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.blocks.as_flattened().as_flattened()[index]
-    }
-}
-
-impl<'a, const SIZE: usize> IndexMut<usize> for ExtractedChunk<SIZE> {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.blocks.as_flattened_mut().as_flattened_mut()[index]
+        self.blocks.get(index)
     }
 }
 
-impl<'a, const SIZE: usize> Index<IVec3> for ExtractedChunk<SIZE> {
+impl<const SIZE: usize> Index<IVec3> for ExtractedChunk<SIZE> {
     type Output = BlockState;
 
     fn index(&self, index: IVec3) -> &Self::Output {
-        let index = index.x as usize * SIZE * SIZE + index.y as usize * SIZE + index.z as usize;
-        &self.blocks.as_flattened().as_flattened()[index]
-    }
-}
-
-impl<'a, const SIZE: usize> IndexMut<IVec3> for ExtractedChunk<SIZE> {
-    fn index_mut(&mut self, index: IVec3) -> &mut Self::Output {
-        let index = index.x as usize * SIZE * SIZE + index.y as usize * SIZE + index.z as usize;
-        &mut self.blocks.as_flattened_mut().as_flattened_mut()[index]
-    }
-}
-
-impl<const SIZE: usize> ToCubes for ExtractedChunk<SIZE> {
-    fn to_cubes(&self) -> Vec<Cube> {
-        let mut cubes = Vec::with_capacity(256);
-        for i in 0..(SIZE * SIZE * SIZE) {
-            let block = self[i];
-            let pos = index_to_pos(i, SIZE);
-            cubes.append(
-                &mut block
-                    .to_cubes()
-                    .iter_mut()
-                    .map(|cube| {
-                        cube.transform.x_axis.w += pos.x as f32;
-                        cube.transform.y_axis.w += pos.y as f32;
-                        cube.transform.z_axis.w += pos.z as f32;
-                        *cube
-                    })
-                    .collect::<Vec<_>>(),
-            );
+        self.blocks.get(pos_to_index(index, SIZE))
+    }
+}
+
+impl<const SIZE: usize> ExtractedChunk<SIZE> {
+    /// Meshes the chunk using `mode`, consulting `neighbours` for blocks
+    /// across the chunk border.
+    pub fn to_packed_faces(
+        &self,
+        registry: &BlockRegistry,
+        neighbours: &ChunkNeighbours,
+        mode: MeshingMode,
+    ) -> Vec<PackedFace> {
+        match mode {
+            MeshingMode::Culled => cull_faces::<SIZE>(&self.blocks, neighbours, registry)
+                .into_iter()
+                .map(|face| PackedFace::new(&Quad::from(face)))
+                .collect(),
+            MeshingMode::Greedy => greedy_quads::<SIZE>(&self.blocks, neighbours, registry)
+                .iter()
+                .map(PackedFace::new)
+                .collect(),
         }
-        cubes
     }
 }
@@ -0,0 +1,361 @@
+use std::borrow::Cow;
+
+use bevy::math::{DVec3, I64Vec3};
+
+use crate::world::{block::BlockState, chunk::index_to_pos};
+
+use super::{
+    combinators::SurfaceLayers,
+    noise::{split_mix, Fbm, Noise, Perlin, Simplex},
+    WorldGenerator,
+};
+
+/// Position in climate space, each axis roughly within `-1.0..=1.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Climate {
+    pub temperature: f64,
+    pub humidity: f64,
+    pub elevation: f64,
+}
+
+impl Climate {
+    pub fn new(temperature: f64, humidity: f64, elevation: f64) -> Self {
+        Self {
+            temperature,
+            humidity,
+            elevation,
+        }
+    }
+
+    fn as_dvec3(&self) -> DVec3 {
+        DVec3::new(self.temperature, self.humidity, self.elevation)
+    }
+
+    pub fn distance(&self, other: &Climate) -> f64 {
+        self.as_dvec3().distance(other.as_dvec3())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Biome {
+    pub name: Cow<'static, str>,
+    /// Climate the biome is found in; each column gets the biome nearest
+    /// to its climate.
+    pub climate: Climate,
+    /// Height of the surface where the terrain noise is zero.
+    pub base_height: f64,
+    /// Distance the surface rises and falls around `base_height`.
+    pub amplitude: f64,
+    pub surface: SurfaceLayers,
+}
+
+impl Biome {
+    pub fn plains() -> Self {
+        Self {
+            name: "plains".into(),
+            climate: Climate::new(0.0, 0.0, 0.0),
+            base_height: 0.0,
+            amplitude: 8.0,
+            surface: SurfaceLayers {
+                top: BlockState::GRASS,
+                filler: BlockState::DIRT,
+                filler_depth: 3,
+                stone: BlockState::STONE,
+            },
+        }
+    }
+
+    pub fn desert() -> Self {
+        Self {
+            name: "desert".into(),
+            climate: Climate::new(0.6, -0.6, 0.0),
+            base_height: 2.0,
+            amplitude: 6.0,
+            surface: SurfaceLayers {
+                top: BlockState::SAND,
+                filler: BlockState::SAND,
+                filler_depth: 4,
+                stone: BlockState::STONE,
+            },
+        }
+    }
+
+    pub fn tundra() -> Self {
+        Self {
+            name: "tundra".into(),
+            climate: Climate::new(-0.6, 0.0, 0.0),
+            base_height: 0.0,
+            amplitude: 10.0,
+            surface: SurfaceLayers {
+                top: BlockState::SNOW,
+                filler: BlockState::DIRT,
+                filler_depth: 2,
+                stone: BlockState::STONE,
+            },
+        }
+    }
+
+    pub fn mountains() -> Self {
+        Self {
+            name: "mountains".into(),
+            climate: Climate::new(0.0, 0.0, 0.7),
+            base_height: 24.0,
+            amplitude: 48.0,
+            surface: SurfaceLayers {
+                top: BlockState::STONE,
+                filler: BlockState::STONE,
+                filler_depth: 0,
+                stone: BlockState::STONE,
+            },
+        }
+    }
+
+    /// The built-in biomes.
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::plains(), Self::desert(), Self::tundra(), Self::mountains()]
+    }
+}
+
+/// Assigns [`Biome`]s to columns from temperature, humidity and elevation
+/// noise.
+#[derive(Clone)]
+pub struct BiomeMap {
+    pub seed: u64,
+    biomes: Vec<Biome>,
+    temperature: Fbm<Simplex>,
+    humidity: Fbm<Simplex>,
+    elevation: Fbm<Simplex>,
+    /// Climate distance over which the heights of neighbouring biomes are
+    /// blended.
+    pub blend: f64,
+}
+
+impl BiomeMap {
+    /// # Panics
+    ///
+    /// If `biomes` is empty.
+    pub fn new(seed: u64, biomes: Vec<Biome>) -> Self {
+        assert!(!biomes.is_empty(), "a biome map needs at least one biome");
+
+        let mut state = seed;
+        let mut climate_noise = |frequency| {
+            Fbm::new(Simplex::new(split_mix(&mut state)))
+                .with_octaves(3)
+                .with_frequency(frequency)
+        };
+
+        Self {
+            seed,
+            biomes,
+            temperature: climate_noise(1.0 / 512.0),
+            humidity: climate_noise(1.0 / 512.0),
+            elevation: climate_noise(1.0 / 768.0),
+            blend: 0.15,
+        }
+    }
+
+    pub fn biomes(&self) -> &[Biome] {
+        &self.biomes
+    }
+
+    /// Fbm rarely reaches its full range, so the climate is stretched to
+    /// make the outer biomes reachable.
+    pub fn climate_at(&self, x: i64, z: i64) -> Climate {
+        let (x, z) = (x as f64, z as f64);
+        Climate::new(
+            self.temperature.get_2d(x, z) * 2.0,
+            self.humidity.get_2d(x, z) * 2.0,
+            self.elevation.get_2d(x, z) * 2.0,
+        )
+    }
+
+    /// Biome of the column containing `pos`.
+    pub fn biome_at(&self, pos: I64Vec3) -> &Biome {
+        self.nearest(&self.climate_at(pos.x, pos.z))
+    }
+
+    fn nearest(&self, climate: &Climate) -> &Biome {
+        self.biomes
+            .iter()
+            .min_by(|a, b| {
+                a.climate
+                    .distance(climate)
+                    .total_cmp(&b.climate.distance(climate))
+            })
+            .unwrap()
+    }
+
+    /// Biomes within `blend` of the nearest one to `climate`, weighted
+    /// linearly by how much nearer they are. The weights sum to one.
+    pub fn weights(&self, climate: &Climate) -> Vec<(&Biome, f64)> {
+        let distances = self
+            .biomes
+            .iter()
+            .map(|biome| (biome, biome.climate.distance(climate)))
+            .collect::<Vec<_>>();
+        let nearest = distances
+            .iter()
+            .map(|(_, distance)| *distance)
+            .fold(f64::INFINITY, f64::min);
+
+        let mut weights = distances
+            .into_iter()
+            .map(|(biome, distance)| (biome, (self.blend - (distance - nearest)).max(0.0)))
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect::<Vec<_>>();
+
+        if weights.is_empty() {
+            // `blend` is zero, so only the nearest biome counts.
+            return vec![(self.nearest(climate), 1.0)];
+        }
+
+        let total = weights.iter().map(|(_, weight)| weight).sum::<f64>();
+        for (_, weight) in &mut weights {
+            *weight /= total;
+        }
+        weights
+    }
+}
+
+/// Terrain shaped and surfaced by biome, with heights blended across biome
+/// borders.
+#[derive(Clone)]
+pub struct BiomeTerrain {
+    pub biomes: BiomeMap,
+    pub noise: Fbm<Perlin>,
+}
+
+impl BiomeTerrain {
+    /// Terrain of the [default biomes](Biome::defaults).
+    pub fn new(seed: u64) -> Self {
+        Self::with_biomes(seed, Biome::defaults())
+    }
+
+    pub fn with_biomes(seed: u64, biomes: Vec<Biome>) -> Self {
+        Self {
+            biomes: BiomeMap::new(seed, biomes),
+            noise: Fbm::new(Perlin::new(seed))
+                .with_octaves(5)
+                .with_frequency(1.0 / 128.0),
+        }
+    }
+
+    /// Height of the first air block in the column at `x`, `z` and the
+    /// biome painting its surface.
+    pub fn column(&self, x: i64, z: i64) -> (i64, &Biome) {
+        let climate = self.biomes.climate_at(x, z);
+        let noise = self.noise.get_2d(x as f64, z as f64);
+        let height = self
+            .biomes
+            .weights(&climate)
+            .into_iter()
+            .map(|(biome, weight)| (biome.base_height + biome.amplitude * noise) * weight)
+            .sum::<f64>();
+        (height.floor() as i64, self.biomes.nearest(&climate))
+    }
+
+    fn column_block(y: i64, height: i64, biome: &Biome) -> BlockState {
+        let surface = &biome.surface;
+        let depth = height - 1 - y;
+        if depth < 0 {
+            BlockState::AIR
+        } else if depth == 0 {
+            surface.top
+        } else if depth <= surface.filler_depth as i64 {
+            surface.filler
+        } else {
+            surface.stone
+        }
+    }
+}
+
+impl WorldGenerator for BiomeTerrain {
+    fn seed(&self) -> u64 {
+        self.biomes.seed
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        let (height, biome) = self.column(pos.x, pos.z);
+        Self::column_block(pos.y, height, biome)
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        let columns = (0..size * size)
+            .map(|i| self.column(origin.x + (i / size) as i64, origin.z + (i % size) as i64))
+            .collect::<Vec<_>>();
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let pos = index_to_pos(i, size);
+            let (height, biome) = columns[pos.x as usize * size + pos.z as usize];
+            *block = Self::column_block(origin.y + pos.y as i64, height, biome);
+        }
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        Some(self.biomes.biome_at(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_sum_to_one() {
+        let map = BiomeMap::new(7, Biome::defaults());
+        for x in (-4000..4000).step_by(61) {
+            for z in (-4000..4000).step_by(67) {
+                let weights = map.weights(&map.climate_at(x, z));
+                let total = weights.iter().map(|(_, weight)| weight).sum::<f64>();
+                assert!((total - 1.0).abs() < 1e-9, "weights at {x}, {z} sum to {total}");
+            }
+        }
+
+        // Halfway between plains and desert.
+        let weights = map.weights(&Climate::new(0.3, -0.3, 0.0));
+        assert_eq!(weights.len(), 2);
+        assert!(weights.iter().all(|(_, weight)| (weight - 0.5).abs() < 1e-9));
+    }
+
+    #[test]
+    fn same_seed_is_identical() {
+        let (a, b, c) = (
+            BiomeMap::new(7, Biome::defaults()),
+            BiomeMap::new(7, Biome::defaults()),
+            BiomeMap::new(8, Biome::defaults()),
+        );
+        let biomes = |map: &BiomeMap| {
+            (-4000..4000)
+                .step_by(37)
+                .map(|x| map.biome_at(I64Vec3::new(x, 0, x / 2)).name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(biomes(&a), biomes(&b));
+        assert_ne!(biomes(&a), biomes(&c));
+    }
+
+    #[test]
+    fn blended_height_is_between_biomes() {
+        let biomes = [Biome::plains(), Biome::mountains()];
+        let terrain = BiomeTerrain::with_biomes(7, biomes.to_vec());
+        let mut blended = 0;
+        for x in (-4000..4000).step_by(7) {
+            let climate = terrain.biomes.climate_at(x, 0);
+            if terrain.biomes.weights(&climate).len() < 2 {
+                continue;
+            }
+            blended += 1;
+
+            let noise = terrain.noise.get_2d(x as f64, 0.0);
+            let [a, b] = biomes
+                .each_ref()
+                .map(|biome| (biome.base_height + biome.amplitude * noise).floor() as i64);
+            let (height, _) = terrain.column(x, 0);
+            assert!(
+                (a.min(b)..=a.max(b)).contains(&height),
+                "{height} at {x} is not within {a} and {b}"
+            );
+        }
+        assert!(blended > 0);
+    }
+}
@@ -0,0 +1,161 @@
+use std::ops::Range;
+
+use bevy::math::{DVec3, I64Vec3};
+
+use crate::world::block::BlockState;
+
+use super::{
+    noise::{split_mix, Fbm, Noise, Perlin, Simplex},
+    WorldGenerator,
+};
+
+#[derive(Debug, Clone)]
+pub struct CaveSettings {
+    /// Heights caves are carved at. Reaching above the surface lets caves
+    /// open up into the terrain, leaving overhangs.
+    pub depth: Range<i64>,
+    /// Share of the volume taken by large open caverns, from `0.0` for none
+    /// to `1.0` for about half.
+    pub cheese_density: f64,
+    /// How wide and frequent the winding tunnels are, from `0.0` for none
+    /// to `1.0`.
+    pub worm_density: f64,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        Self {
+            depth: -128..48,
+            cheese_density: 0.3,
+            worm_density: 0.5,
+        }
+    }
+}
+
+/// Solid where caves are carved, so a cave pass runs after the base terrain
+/// as `terrain.subtract(Caves::new(seed))`.
+///
+/// Cheese caves are pockets where 3D noise peaks. Worm caves are tunnels
+/// along the lines where two noise fields both cross zero.
+#[derive(Clone)]
+pub struct Caves {
+    pub seed: u64,
+    pub settings: CaveSettings,
+    cheese: Fbm<Simplex>,
+    worms: [Fbm<Perlin>; 2],
+}
+
+impl Caves {
+    pub fn new(seed: u64) -> Self {
+        Self::with_settings(seed, CaveSettings::default())
+    }
+
+    pub fn with_settings(seed: u64, settings: CaveSettings) -> Self {
+        let mut state = seed;
+        let mut worm = || {
+            Fbm::new(Perlin::new(split_mix(&mut state)))
+                .with_octaves(2)
+                .with_frequency(1.0 / 48.0)
+        };
+        let worms = [worm(), worm()];
+
+        Self {
+            seed,
+            settings,
+            cheese: Fbm::new(Simplex::new(split_mix(&mut state)))
+                .with_octaves(3)
+                .with_frequency(1.0 / 64.0),
+            worms,
+        }
+    }
+
+    fn is_cheese(&self, point: DVec3) -> bool {
+        let density = self.settings.cheese_density;
+        // Fbm rarely exceeds 0.6, so low densities only carve its peaks.
+        density > 0.0 && self.cheese.get(point) > 0.6 * (1.0 - density)
+    }
+
+    fn is_worm(&self, point: DVec3) -> bool {
+        let width = 0.08 * self.settings.worm_density;
+        // Squashing the height keeps tunnels mostly horizontal.
+        let point = point * DVec3::new(1.0, 2.0, 1.0);
+        self.worms.iter().all(|worm| worm.get(point).abs() < width)
+    }
+
+    /// Whether a cave is carved at `pos`.
+    pub fn is_cave(&self, pos: I64Vec3) -> bool {
+        if !self.settings.depth.contains(&pos.y) {
+            return false;
+        }
+        let point = pos.as_dvec3();
+        self.is_worm(point) || self.is_cheese(point)
+    }
+}
+
+impl WorldGenerator for Caves {
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        if self.is_cave(pos) {
+            BlockState::STONE
+        } else {
+            BlockState::AIR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: I64Vec3 = I64Vec3::new(-16, -64, 48);
+
+    /// Whether each block of a 32³ volume from `origin` is carved.
+    fn carved(caves: &Caves, origin: I64Vec3) -> Vec<bool> {
+        (0..32 * 32 * 32)
+            .map(|i| origin + I64Vec3::new(i / 1024, i / 32 % 32, i % 32))
+            .map(|pos| caves.is_cave(pos))
+            .collect()
+    }
+
+    #[test]
+    fn zero_density_carves_nothing() {
+        let caves = Caves::with_settings(
+            42,
+            CaveSettings {
+                cheese_density: 0.0,
+                worm_density: 0.0,
+                ..CaveSettings::default()
+            },
+        );
+        assert!(!carved(&caves, ORIGIN).contains(&true));
+    }
+
+    #[test]
+    fn carves_only_within_depth() {
+        let caves = Caves::with_settings(
+            42,
+            CaveSettings {
+                depth: -32..0,
+                cheese_density: 1.0,
+                worm_density: 1.0,
+            },
+        );
+        let below = carved(&caves, I64Vec3::new(-16, -64, 48));
+        let within = carved(&caves, I64Vec3::new(-16, -32, 48));
+        let above = carved(&caves, I64Vec3::new(-16, 0, 48));
+        assert!(!below.contains(&true));
+        assert!(within.contains(&true));
+        assert!(!above.contains(&true));
+    }
+
+    #[test]
+    fn same_seed_is_identical() {
+        let carved = |seed| carved(&Caves::new(seed), ORIGIN);
+        assert!(carved(42).contains(&true));
+        assert_eq!(carved(42), carved(42));
+        assert_ne!(carved(42), carved(43));
+    }
+}
@@ -0,0 +1,579 @@
+//! Generators built from other generators, so terrain can be assembled from
+//! small pieces. A block counts as solid when it is not air.
+//!
+//! Combinators report the seed and biomes of their first generator and
+//! forward [`WorldGenerator::generate_chunk`] to their parts where possible,
+//! so batched generators stay batched.
+
+use std::ops::Range;
+
+use bevy::math::I64Vec3;
+
+use crate::world::{block::BlockState, chunk::index_to_pos};
+
+use super::{biome::Biome, noise::Noise, WorldGenerator};
+
+/// Builds combinators from any [`WorldGenerator`].
+pub trait GeneratorExt: WorldGenerator + Sized {
+    fn union<B: WorldGenerator>(self, other: B) -> Union<Self, B> {
+        Union(self, other)
+    }
+
+    fn intersect<B: WorldGenerator>(self, other: B) -> Intersect<Self, B> {
+        Intersect(self, other)
+    }
+
+    fn subtract<B: WorldGenerator>(self, other: B) -> Subtract<Self, B> {
+        Subtract(self, other)
+    }
+
+    fn offset(self, offset: I64Vec3) -> Offset<Self> {
+        Offset {
+            generator: self,
+            offset,
+        }
+    }
+
+    fn scale(self, factor: I64Vec3) -> Scale<Self> {
+        Scale::new(self, factor)
+    }
+
+    fn clamp_height(self, heights: Range<i64>) -> ClampHeight<Self> {
+        ClampHeight {
+            generator: self,
+            heights,
+        }
+    }
+
+    fn surface(self, layers: SurfaceLayers) -> Surface<Self> {
+        Surface {
+            shape: self,
+            layers,
+        }
+    }
+}
+
+impl<G: WorldGenerator> GeneratorExt for G {}
+
+fn is_solid(block: BlockState) -> bool {
+    block != BlockState::AIR
+}
+
+/// Generates `generator` into a new buffer, for combinators merging several
+/// generators.
+fn generate(generator: &impl WorldGenerator, origin: I64Vec3, size: usize) -> Vec<BlockState> {
+    let mut blocks = vec![BlockState::AIR; size * size * size];
+    generator.generate_chunk(origin, size, &mut blocks);
+    blocks
+}
+
+/// Blocks of `A`, with the air in it filled by `B`.
+#[derive(Clone)]
+pub struct Union<A, B>(pub A, pub B);
+
+impl<A: WorldGenerator, B: WorldGenerator> WorldGenerator for Union<A, B> {
+    fn seed(&self) -> u64 {
+        self.0.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.0.biome_at(pos)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        let block = self.0.block(pos);
+        if is_solid(block) { block } else { self.1.block(pos) }
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        self.0.generate_chunk(origin, size, blocks);
+        let other = generate(&self.1, origin, size);
+        for (block, other) in blocks.iter_mut().zip(other) {
+            if !is_solid(*block) {
+                *block = other;
+            }
+        }
+    }
+}
+
+/// Blocks of `A` where `B` is solid too.
+#[derive(Clone)]
+pub struct Intersect<A, B>(pub A, pub B);
+
+impl<A: WorldGenerator, B: WorldGenerator> WorldGenerator for Intersect<A, B> {
+    fn seed(&self) -> u64 {
+        self.0.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.0.biome_at(pos)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        let block = self.0.block(pos);
+        if is_solid(block) && is_solid(self.1.block(pos)) { block } else { BlockState::AIR }
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        self.0.generate_chunk(origin, size, blocks);
+        let other = generate(&self.1, origin, size);
+        for (block, other) in blocks.iter_mut().zip(other) {
+            if !is_solid(other) {
+                *block = BlockState::AIR;
+            }
+        }
+    }
+}
+
+/// Blocks of `A` where `B` is air, e.g. terrain with caves carved out.
+#[derive(Clone)]
+pub struct Subtract<A, B>(pub A, pub B);
+
+impl<A: WorldGenerator, B: WorldGenerator> WorldGenerator for Subtract<A, B> {
+    fn seed(&self) -> u64 {
+        self.0.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.0.biome_at(pos)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        if is_solid(self.1.block(pos)) { BlockState::AIR } else { self.0.block(pos) }
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        self.0.generate_chunk(origin, size, blocks);
+        let other = generate(&self.1, origin, size);
+        for (block, other) in blocks.iter_mut().zip(other) {
+            if is_solid(other) {
+                *block = BlockState::AIR;
+            }
+        }
+    }
+}
+
+/// `generator` moved by `offset` blocks.
+#[derive(Clone)]
+pub struct Offset<G> {
+    pub generator: G,
+    pub offset: I64Vec3,
+}
+
+impl<G: WorldGenerator> WorldGenerator for Offset<G> {
+    fn seed(&self) -> u64 {
+        self.generator.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.generator.biome_at(pos - self.offset)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        self.generator.block(pos - self.offset)
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        self.generator.generate_chunk(origin - self.offset, size, blocks);
+    }
+}
+
+/// `generator` stretched by `factor` along each axis, every block becoming
+/// a `factor`-sized box.
+#[derive(Clone)]
+pub struct Scale<G> {
+    pub generator: G,
+    factor: I64Vec3,
+}
+
+impl<G> Scale<G> {
+    /// # Panics
+    ///
+    /// If any component of `factor` is not positive.
+    pub fn new(generator: G, factor: I64Vec3) -> Self {
+        assert!(factor.cmpgt(I64Vec3::ZERO).all(), "scale factor must be positive");
+        Self { generator, factor }
+    }
+
+    pub fn factor(&self) -> I64Vec3 {
+        self.factor
+    }
+}
+
+impl<G: WorldGenerator> WorldGenerator for Scale<G> {
+    fn seed(&self) -> u64 {
+        self.generator.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.generator.biome_at(pos.div_euclid(self.factor))
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        self.generator.block(pos.div_euclid(self.factor))
+    }
+}
+
+/// `generator` within `heights`, air above and below.
+#[derive(Clone)]
+pub struct ClampHeight<G> {
+    pub generator: G,
+    pub heights: Range<i64>,
+}
+
+impl<G: WorldGenerator> WorldGenerator for ClampHeight<G> {
+    fn seed(&self) -> u64 {
+        self.generator.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.generator.biome_at(pos)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        if self.heights.contains(&pos.y) { self.generator.block(pos) } else { BlockState::AIR }
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        // Skip generating chunks entirely outside the range.
+        if origin.y >= self.heights.end || origin.y + size as i64 <= self.heights.start {
+            blocks.fill(BlockState::AIR);
+            return;
+        }
+        self.generator.generate_chunk(origin, size, blocks);
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if !self.heights.contains(&(origin.y + index_to_pos(i, size).y as i64)) {
+                *block = BlockState::AIR;
+            }
+        }
+    }
+}
+
+/// Solid wherever `noise` exceeds `threshold`, for use as the mask of
+/// [`Select`] or with the other combinators.
+#[derive(Clone)]
+pub struct NoiseMask<N> {
+    pub seed: u64,
+    pub noise: N,
+    pub threshold: f64,
+    /// Block filling the solid part.
+    pub block: BlockState,
+}
+
+impl<N: Noise> NoiseMask<N> {
+    pub fn new(seed: u64, noise: N, threshold: f64) -> Self {
+        Self {
+            seed,
+            noise,
+            threshold,
+            block: BlockState::STONE,
+        }
+    }
+}
+
+impl<N: Noise> WorldGenerator for NoiseMask<N> {
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        if self.noise.get(pos.as_dvec3()) > self.threshold { self.block } else { BlockState::AIR }
+    }
+}
+
+/// Blocks of `solid` where `mask` is solid and of `air` elsewhere.
+#[derive(Clone)]
+pub struct Select<M, A, B> {
+    pub mask: M,
+    pub solid: A,
+    pub air: B,
+}
+
+impl<M, A, B> Select<M, A, B> {
+    pub fn new(mask: M, solid: A, air: B) -> Self {
+        Self { mask, solid, air }
+    }
+}
+
+impl<M: WorldGenerator, A: WorldGenerator, B: WorldGenerator> WorldGenerator for Select<M, A, B> {
+    fn seed(&self) -> u64 {
+        self.solid.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.solid.biome_at(pos)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        if is_solid(self.mask.block(pos)) { self.solid.block(pos) } else { self.air.block(pos) }
+    }
+
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        let mask = generate(&self.mask, origin, size);
+        let solid = generate(&self.solid, origin, size);
+        self.air.generate_chunk(origin, size, blocks);
+        for ((block, mask), solid) in blocks.iter_mut().zip(mask).zip(solid) {
+            if is_solid(mask) {
+                *block = solid;
+            }
+        }
+    }
+}
+
+/// Blocks replacing the solid part of a shape by depth below the surface.
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceLayers {
+    /// Solid blocks with air directly above, e.g. grass.
+    pub top: BlockState,
+    /// Solid blocks with air at most `filler_depth` blocks above, e.g. dirt.
+    pub filler: BlockState,
+    pub filler_depth: u32,
+    /// The remaining solid blocks.
+    pub stone: BlockState,
+}
+
+/// The solid part of `shape` painted with [`SurfaceLayers`].
+#[derive(Clone)]
+pub struct Surface<G> {
+    pub shape: G,
+    pub layers: SurfaceLayers,
+}
+
+impl<G> Surface<G> {
+    /// Layer of a solid block with `depth` solid blocks between it and the
+    /// air above.
+    fn layer(&self, depth: u32) -> BlockState {
+        if depth == 0 {
+            self.layers.top
+        } else if depth <= self.layers.filler_depth {
+            self.layers.filler
+        } else {
+            self.layers.stone
+        }
+    }
+}
+
+impl<G: WorldGenerator> WorldGenerator for Surface<G> {
+    fn seed(&self) -> u64 {
+        self.shape.seed()
+    }
+
+    fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.shape.biome_at(pos)
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        if !is_solid(self.shape.block(pos)) {
+            return BlockState::AIR;
+        }
+        let depth = (1..=self.layers.filler_depth as i64 + 1)
+            .find(|dy| !is_solid(self.shape.block(pos + I64Vec3::Y * dy)))
+            .map_or(self.layers.filler_depth + 1, |dy| dy as u32 - 1);
+        self.layer(depth)
+    }
+
+    /// Walks each column down from above the chunk, so the shape is sampled
+    /// once per block plus `filler_depth + 1` blocks per column.
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        let shape = generate(&self.shape, origin, size);
+        let above = self.layers.filler_depth as i64 + 1;
+
+        for x in 0..size {
+            for z in 0..size {
+                let column = origin + I64Vec3::new(x as i64, 0, z as i64);
+                // Solid blocks since the last air, starting above the chunk.
+                let mut depth = (1..=above)
+                    .rev()
+                    .map(|dy| self.shape.block(column + I64Vec3::Y * (size as i64 - 1 + dy)))
+                    .fold(None, |depth: Option<u32>, block| {
+                        if is_solid(block) { Some(depth.map_or(0, |depth| depth + 1)) } else { None }
+                    });
+
+                for y in (0..size).rev() {
+                    let i = x * size * size + y * size + z;
+                    if is_solid(shape[i]) {
+                        let block_depth = depth.map_or(0, |depth| depth + 1);
+                        blocks[i] = self.layer(block_depth);
+                        depth = Some(block_depth);
+                    } else {
+                        blocks[i] = BlockState::AIR;
+                        depth = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::generation::{
+        flat,
+        noise::{Fbm, Perlin, Simplex},
+        terrain::HeightmapTerrain,
+    };
+
+    const SIZE: usize = 16;
+
+    /// Origins of chunks around the world origin, on both sides of it.
+    const ORIGINS: [I64Vec3; 3] = [
+        I64Vec3::new(0, 0, 0),
+        I64Vec3::new(-16, -16, -16),
+        I64Vec3::new(32, -8, -48),
+    ];
+
+    fn hills() -> HeightmapTerrain {
+        let mut terrain = HeightmapTerrain::new(1);
+        terrain.amplitude = 12.0;
+        terrain
+    }
+
+    fn blobs() -> NoiseMask<Fbm<Simplex>> {
+        NoiseMask::new(2, Fbm::new(Simplex::new(2)).with_frequency(1.0 / 8.0), 0.1)
+    }
+
+    fn assert_chunk_matches_blocks(generator: &impl WorldGenerator, origin: I64Vec3) {
+        let blocks = generate(generator, origin, SIZE);
+        for (i, block) in blocks.into_iter().enumerate() {
+            let pos = origin + index_to_pos(i, SIZE).as_i64vec3();
+            assert_eq!(block, generator.block(pos), "at {pos}");
+        }
+    }
+
+    fn assert_matches(generator: impl WorldGenerator) {
+        for origin in ORIGINS {
+            assert_chunk_matches_blocks(&generator, origin);
+        }
+    }
+
+    #[test]
+    fn union() {
+        assert_matches(hills().union(blobs()));
+        let sand = |_| BlockState::SAND;
+        assert_eq!(flat::<0>.union(sand).block(I64Vec3::new(0, -1, 0)), BlockState::STONE);
+        assert_eq!(flat::<0>.union(sand).block(I64Vec3::new(0, 0, 0)), BlockState::SAND);
+    }
+
+    #[test]
+    fn intersect() {
+        assert_matches(hills().intersect(blobs()));
+        assert_eq!(flat::<0>.intersect(flat::<-4>).block(I64Vec3::new(0, -2, 0)), BlockState::AIR);
+        assert_eq!(flat::<0>.intersect(flat::<-4>).block(I64Vec3::new(0, -5, 0)), BlockState::STONE);
+    }
+
+    #[test]
+    fn subtract() {
+        assert_matches(hills().subtract(blobs()));
+        assert_eq!(flat::<0>.subtract(flat::<-4>).block(I64Vec3::new(0, -2, 0)), BlockState::STONE);
+        assert_eq!(flat::<0>.subtract(flat::<-4>).block(I64Vec3::new(0, -5, 0)), BlockState::AIR);
+    }
+
+    #[test]
+    fn offset() {
+        assert_matches(hills().offset(I64Vec3::new(-7, 5, 300)));
+        let raised = flat::<0>.offset(I64Vec3::new(0, 3, 0));
+        assert_eq!(raised.block(I64Vec3::new(0, 2, 0)), BlockState::STONE);
+        assert_eq!(raised.block(I64Vec3::new(0, 3, 0)), BlockState::AIR);
+    }
+
+    #[test]
+    fn scale() {
+        assert_matches(hills().scale(I64Vec3::new(2, 3, 2)));
+
+        // Block `-1` covers `-2..0` once doubled, rather than being split
+        // across zero.
+        let pillar = |pos: I64Vec3| if pos.x == -1 { BlockState::STONE } else { BlockState::AIR };
+        let scaled = pillar.scale(I64Vec3::splat(2));
+        let solid = (-4..4)
+            .filter(|x| scaled.block(I64Vec3::new(*x, -3, 5)) != BlockState::AIR)
+            .collect::<Vec<_>>();
+        assert_eq!(solid, [-2, -1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "scale factor must be positive")]
+    fn scale_rejects_non_positive_factors() {
+        flat::<0>.scale(I64Vec3::new(1, 0, 1));
+    }
+
+    #[test]
+    fn clamp_height() {
+        // Chunks partly in range, entirely in range and entirely outside.
+        assert_matches(hills().clamp_height(-4..4));
+        assert_matches(hills().clamp_height(-100..100));
+        assert_matches(hills().clamp_height(100..200));
+
+        let solid = |_| BlockState::STONE;
+        let blocks = generate(&solid.clamp_height(-4..4), I64Vec3::new(0, -8, 0), SIZE);
+        let solid_layers = (0..SIZE)
+            .filter(|y| blocks[y * SIZE] != BlockState::AIR)
+            .collect::<Vec<_>>();
+        assert_eq!(solid_layers, [4, 5, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn select() {
+        let sand = |_| BlockState::SAND;
+        assert_matches(Select::new(blobs(), hills(), sand));
+        assert_matches(Select::new(blobs(), sand, flat::<0>));
+    }
+
+    #[test]
+    fn noise_mask() {
+        assert_matches(blobs());
+        let mask = NoiseMask::new(3, Fbm::new(Perlin::new(3)), -1.5);
+        assert_eq!(mask.block(I64Vec3::new(5, -5, 5)), BlockState::STONE);
+    }
+
+    const LAYERS: SurfaceLayers = SurfaceLayers {
+        top: BlockState::GRASS,
+        filler: BlockState::DIRT,
+        filler_depth: 3,
+        stone: BlockState::STONE,
+    };
+
+    /// Blocks of the column at `x = z = 0` of the chunk at the origin, from
+    /// the top of the chunk down.
+    fn column_from_top(generator: &impl WorldGenerator) -> Vec<BlockState> {
+        let blocks = generate(generator, I64Vec3::ZERO, SIZE);
+        (0..SIZE).rev().map(|y| blocks[y * SIZE]).collect()
+    }
+
+    #[test]
+    fn surface() {
+        assert_matches(hills().surface(LAYERS));
+        assert_matches(hills().union(blobs()).surface(LAYERS));
+    }
+
+    #[test]
+    fn surface_inside_chunk() {
+        use BlockState as B;
+        let column = column_from_top(&flat::<14>.surface(LAYERS));
+        assert_eq!(
+            column[..7],
+            [B::AIR, B::AIR, B::GRASS, B::DIRT, B::DIRT, B::DIRT, B::STONE]
+        );
+    }
+
+    /// The surface is above the chunk, so the depth of the top layers comes
+    /// from the blocks sampled above it.
+    #[test]
+    fn surface_above_chunk() {
+        use BlockState as B;
+        for (level, expected) in [
+            (16, [B::GRASS, B::DIRT, B::DIRT, B::DIRT, B::STONE]),
+            (17, [B::DIRT, B::DIRT, B::DIRT, B::STONE, B::STONE]),
+            (18, [B::DIRT, B::DIRT, B::STONE, B::STONE, B::STONE]),
+            (19, [B::DIRT, B::STONE, B::STONE, B::STONE, B::STONE]),
+            (20, [B::STONE; 5]),
+        ] {
+            let shape = move |pos: I64Vec3| {
+                if pos.y < level { BlockState::STONE } else { BlockState::AIR }
+            };
+            let generator = shape.surface(LAYERS);
+            assert_eq!(column_from_top(&generator)[..5], expected, "surface at {level}");
+            assert_chunk_matches_blocks(&generator, I64Vec3::ZERO);
+        }
+    }
+}
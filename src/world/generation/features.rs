@@ -0,0 +1,348 @@
+//! Multi-block features such as trees and boulders, placed after the
+//! terrain of a chunk is generated.
+//!
+//! Placement only depends on the level seed, the chunk coordinate and the
+//! chunk's own blocks, so features come out the same however the world is
+//! explored. A feature may reach into neighbouring chunks; those writes are
+//! queued until the neighbour is loaded, see
+//! [`FeatureWrites`](crate::world::FeatureWrites).
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::Arc,
+};
+
+use bevy::math::{I64Vec3, IVec3};
+
+use crate::world::{block::BlockState, chunk::Chunk, split_world_pos};
+
+use super::{noise::split_mix, WorldGenerator};
+
+/// A block written by a [`Feature`], in world coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureWrite {
+    pub pos: I64Vec3,
+    pub block: BlockState,
+    /// Whether to overwrite solid blocks rather than only fill air.
+    pub replace: bool,
+}
+
+/// Collects the blocks a [`Feature`] places.
+#[derive(Debug, Default)]
+pub struct FeatureWriter {
+    writes: Vec<FeatureWrite>,
+}
+
+impl FeatureWriter {
+    /// Places `block` at `pos` if it is air.
+    pub fn place(&mut self, pos: I64Vec3, block: BlockState) {
+        self.writes.push(FeatureWrite {
+            pos,
+            block,
+            replace: false,
+        });
+    }
+
+    /// Places `block` at `pos` whatever is there.
+    pub fn replace(&mut self, pos: I64Vec3, block: BlockState) {
+        self.writes.push(FeatureWrite {
+            pos,
+            block,
+            replace: true,
+        });
+    }
+}
+
+/// Random numbers for placing features, seeded from the level seed and the
+/// chunk coordinate.
+#[derive(Debug, Clone)]
+pub struct FeatureRng(u64);
+
+impl FeatureRng {
+    pub fn new(seed: u64, chunk: I64Vec3, salt: u64) -> Self {
+        let seed = [chunk.x as u64, chunk.y as u64, chunk.z as u64, salt]
+            .into_iter()
+            .fold(seed, |hash, value| split_mix(&mut (hash ^ value)));
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        split_mix(&mut self.0)
+    }
+
+    /// Uniform in `range`.
+    pub fn range(&mut self, range: RangeInclusive<i64>) -> i64 {
+        let span = (range.end() - range.start()) as u64 + 1;
+        range.start() + (self.next_u64() % span) as i64
+    }
+
+    /// `true` with probability `chance`.
+    pub fn chance(&mut self, chance: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < chance
+    }
+}
+
+/// A multi-block structure placed on the terrain.
+pub trait Feature: Send + Sync + 'static {
+    /// Writes the feature standing on the ground below `origin`, which is
+    /// the first air block above the surface.
+    fn place(&self, origin: I64Vec3, rng: &mut FeatureRng, writer: &mut FeatureWriter);
+}
+
+/// A trunk topped with a ball of leaves.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    pub trunk: BlockState,
+    pub leaves: BlockState,
+    pub height: RangeInclusive<i64>,
+}
+
+impl Default for Tree {
+    fn default() -> Self {
+        Self {
+            trunk: BlockState::LOG,
+            leaves: BlockState::LEAVES,
+            height: 4..=6,
+        }
+    }
+}
+
+impl Feature for Tree {
+    fn place(&self, origin: I64Vec3, rng: &mut FeatureRng, writer: &mut FeatureWriter) {
+        let height = rng.range(self.height.clone());
+        let top = origin + I64Vec3::Y * height;
+
+        for dy in -2..=1_i64 {
+            let radius: i64 = if dy == 1 { 1 } else { 2 };
+            for dx in -radius..=radius {
+                for dz in -radius..=radius {
+                    // Round off the corners.
+                    if dx.abs() == radius && dz.abs() == radius && (dy == 1 || rng.chance(0.5)) {
+                        continue;
+                    }
+                    writer.place(top + I64Vec3::new(dx, dy, dz), self.leaves);
+                }
+            }
+        }
+
+        for dy in 0..height {
+            writer.replace(origin + I64Vec3::Y * dy, self.trunk);
+        }
+    }
+}
+
+/// A rough ball of rock half buried in the ground.
+#[derive(Debug, Clone)]
+pub struct Boulder {
+    pub block: BlockState,
+    pub radius: RangeInclusive<i64>,
+}
+
+impl Default for Boulder {
+    fn default() -> Self {
+        Self {
+            block: BlockState::STONE,
+            radius: 1..=3,
+        }
+    }
+}
+
+impl Feature for Boulder {
+    fn place(&self, origin: I64Vec3, rng: &mut FeatureRng, writer: &mut FeatureWriter) {
+        let radius = rng.range(self.radius.clone());
+        for dx in -radius..=radius {
+            for dy in -radius..=radius {
+                for dz in -radius..=radius {
+                    let offset = I64Vec3::new(dx, dy, dz);
+                    if offset.length_squared() <= radius * radius {
+                        writer.place(origin + offset, self.block);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Where and how often a [`Feature`] is placed.
+#[derive(Clone)]
+pub struct FeaturePlacement {
+    pub feature: Arc<dyn Feature>,
+    /// Columns tried per chunk.
+    pub attempts: u32,
+    /// Chance of each attempt placing the feature.
+    pub chance: f64,
+    /// Only place on this block, e.g. trees on grass.
+    pub ground: Option<BlockState>,
+}
+
+impl FeaturePlacement {
+    pub fn new(feature: impl Feature, attempts: u32) -> Self {
+        Self {
+            feature: Arc::new(feature),
+            attempts,
+            chance: 1.0,
+            ground: None,
+        }
+    }
+
+    pub fn with_chance(mut self, chance: f64) -> Self {
+        self.chance = chance;
+        self
+    }
+
+    pub fn on(mut self, ground: BlockState) -> Self {
+        self.ground = Some(ground);
+        self
+    }
+
+    /// Places the feature on `chunk` at chunk coordinate `chunk_pos`.
+    /// `salt` tells apart placements of the same level.
+    fn place<const SIZE: usize>(
+        &self,
+        generator: &dyn WorldGenerator,
+        salt: u64,
+        chunk_pos: I64Vec3,
+        chunk: &Chunk<SIZE>,
+        writer: &mut FeatureWriter,
+    ) {
+        let mut rng = FeatureRng::new(generator.seed(), chunk_pos, salt);
+        for _ in 0..self.attempts {
+            let x = rng.range(0..=SIZE as i64 - 1) as i32;
+            let z = rng.range(0..=SIZE as i64 - 1) as i32;
+            if !rng.chance(self.chance) {
+                continue;
+            }
+
+            // Highest ground in the column with air above it, so each
+            // surface belongs to the chunk holding its ground block. Above
+            // the top layer, the terrain is asked for the block instead.
+            let is_air_above = |y: i32| {
+                if y + 1 < SIZE as i32 {
+                    chunk[IVec3::new(x, y + 1, z)] == BlockState::AIR
+                } else {
+                    let above = chunk_pos * SIZE as i64 + IVec3::new(x, SIZE as i32, z).as_i64vec3();
+                    generator.block(above) == BlockState::AIR
+                }
+            };
+            let ground = (0..SIZE as i32)
+                .rev()
+                .find(|y| chunk[IVec3::new(x, *y, z)] != BlockState::AIR && is_air_above(*y));
+            let Some(y) = ground else {
+                continue;
+            };
+            if self
+                .ground
+                .is_some_and(|ground| chunk[IVec3::new(x, y, z)] != ground)
+            {
+                continue;
+            }
+
+            let origin = chunk_pos * SIZE as i64 + IVec3::new(x, y + 1, z).as_i64vec3();
+            self.feature.place(origin, &mut rng, writer);
+        }
+    }
+}
+
+/// Places every feature of `placements` on the chunk at chunk coordinate
+/// `chunk_pos`, generated by `generator`, returning the writes grouped by
+/// the chunk they fall in.
+pub fn place_features<const SIZE: usize>(
+    generator: &dyn WorldGenerator,
+    placements: &[FeaturePlacement],
+    chunk_pos: I64Vec3,
+    chunk: &Chunk<SIZE>,
+) -> HashMap<I64Vec3, Vec<FeatureWrite>> {
+    let mut writer = FeatureWriter::default();
+    for (salt, placement) in placements.iter().enumerate() {
+        placement.place(generator, salt as u64, chunk_pos, chunk, &mut writer);
+    }
+
+    let mut by_chunk = HashMap::<_, Vec<_>>::new();
+    for write in writer.writes {
+        let (target, _) = split_world_pos::<SIZE>(write.pos);
+        by_chunk.entry(target).or_default().push(write);
+    }
+    by_chunk
+}
+
+/// Applies the `writes` that fall in `chunk`, at chunk coordinate
+/// `chunk_pos`, in order.
+pub fn apply_feature_writes<const SIZE: usize>(
+    chunk: &mut Chunk<SIZE>,
+    chunk_pos: I64Vec3,
+    writes: &[FeatureWrite],
+) {
+    for write in writes {
+        let (target, local) = split_world_pos::<SIZE>(write.pos);
+        if target != chunk_pos {
+            continue;
+        }
+        if write.replace || chunk[local] == BlockState::AIR {
+            chunk.set_at(local, write.block);
+        }
+    }
+}
+
+/// Sets the blocks at `positions` in `chunk` to what the terrain of
+/// `generator` becomes once `writes` are applied in order, for writes
+/// arriving after the chunk was generated.
+///
+/// `earlier` are the writes the chunk already has. Blocks no longer matching
+/// them were edited since, and are left as they are.
+pub fn replay_feature_writes<const SIZE: usize>(
+    chunk: &mut Chunk<SIZE>,
+    chunk_pos: I64Vec3,
+    generator: &dyn WorldGenerator,
+    earlier: &[FeatureWrite],
+    writes: &[FeatureWrite],
+    positions: &[I64Vec3],
+) {
+    let positions = positions.iter().copied().collect::<HashSet<_>>();
+    let fold = |writes: &[FeatureWrite]| {
+        let mut blocks = positions
+            .iter()
+            .map(|pos| (*pos, generator.block(*pos)))
+            .collect::<HashMap<_, _>>();
+        for write in writes {
+            if let Some(block) = blocks.get_mut(&write.pos) {
+                if write.replace || *block == BlockState::AIR {
+                    *block = write.block;
+                }
+            }
+        }
+        blocks
+    };
+    let before = fold(earlier);
+
+    for (pos, block) in fold(writes) {
+        let (target, local) = split_world_pos::<SIZE>(pos);
+        if target == chunk_pos && chunk[local] == before[&pos] && chunk[local] != block {
+            chunk.set_at(local, block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::generation::terrain::HeightmapTerrain;
+
+    fn place(seed: u64) -> HashMap<I64Vec3, Vec<FeatureWrite>> {
+        let terrain = HeightmapTerrain::new(seed);
+        let placements = [
+            FeaturePlacement::new(Tree::default(), 8),
+            FeaturePlacement::new(Boulder::default(), 2),
+        ];
+        let chunk = Chunk::<16>::generate(&terrain, I64Vec3::ZERO);
+        place_features(&terrain, &placements, I64Vec3::ZERO, &chunk)
+    }
+
+    #[test]
+    fn same_seed_places_the_same() {
+        let writes = place(42);
+        assert!(!writes.is_empty());
+        assert_eq!(writes, place(42));
+        assert_ne!(writes, place(43));
+    }
+}
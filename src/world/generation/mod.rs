@@ -1,15 +1,64 @@
-use bevy::{math::I64Vec3, prelude::*};
+pub mod biome;
+pub mod caves;
+pub mod combinators;
+pub mod features;
+pub mod noise;
+pub mod terrain;
 
-use super::block::BlockState;
+use bevy::math::I64Vec3;
 
-#[derive(Component)]
-pub struct Generator();
+use biome::Biome;
 
-pub type GeneratorFn<T> = fn(I64Vec3) -> T;
-pub type BlockGenerator = GeneratorFn<BlockState>;
+use super::{block::BlockState, chunk::index_to_pos};
+
+/// Fills the blocks of a level. Generators may hold state such as noise
+/// tables or configuration, and are shared between the tasks generating
+/// chunks in parallel.
+pub trait WorldGenerator: Send + Sync + 'static {
+    /// Seed the generator derives its randomness from, `0` if it has none.
+    fn seed(&self) -> u64;
+
+    /// Block at world position `pos`.
+    fn block(&self, pos: I64Vec3) -> BlockState;
+
+    /// Fills `blocks` with the `size`³ blocks starting at `origin`, in chunk
+    /// index order: `x` major, then `y`, then `z`.
+    ///
+    /// Override to share work between the blocks of a chunk, e.g. sampling
+    /// a heightmap once per column.
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = self.block(origin + index_to_pos(i, size).as_i64vec3());
+        }
+    }
+
+    /// Biome of the column containing `pos`, if the generator has biomes.
+    fn biome_at(&self, _pos: I64Vec3) -> Option<&Biome> {
+        None
+    }
+}
+
+/// Plain functions, such as [`flat`] or [`debug::sine`], generate without a
+/// seed.
+impl<F> WorldGenerator for F
+where
+    F: Fn(I64Vec3) -> BlockState + Send + Sync + 'static,
+{
+    fn seed(&self) -> u64 {
+        0
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        self(pos)
+    }
+}
 
 pub fn flat<const LEVEL: i64>(pos: I64Vec3) -> BlockState {
-    pos.y < LEVEL
+    if pos.y < LEVEL {
+        BlockState::STONE
+    } else {
+        BlockState::AIR
+    }
 }
 
 /// Debug block generators
@@ -28,14 +77,18 @@ pub mod debug {
         const PERIOD: i32>
     (pos: I64Vec3)
     -> BlockState {
-        pos.y < (sin(pos.x as f32 * 2.0 * f32::consts::PI / PERIOD as f32) * (AMPLITUDE as f32)) as i64 + LEVEL
+        if pos.y < (sin(pos.x as f32 * 2.0 * f32::consts::PI / PERIOD as f32) * (AMPLITUDE as f32)) as i64 + LEVEL {
+            BlockState::STONE
+        } else {
+            BlockState::AIR
+        }
     }
 
     pub fn empty(_: I64Vec3) -> BlockState {
-        false
+        BlockState::AIR
     }
 
     pub fn full(_: I64Vec3) -> BlockState {
-        true
+        BlockState::STONE
     }
 }
@@ -0,0 +1,409 @@
+//! Seeded gradient and value noise.
+//!
+//! Only uses `+`, `-`, `*`, `/` and `floor` on `f64`, which IEEE 754 defines
+//! exactly, so the same seed gives bit-identical results on every platform.
+
+use bevy::math::DVec3;
+
+/// Deterministic noise, roughly within `-1.0..=1.0`.
+pub trait Noise: Send + Sync + 'static {
+    fn get(&self, point: DVec3) -> f64;
+
+    /// Samples the `y = 0` plane, for heightmaps.
+    fn get_2d(&self, x: f64, z: f64) -> f64 {
+        self.get(DVec3::new(x, 0.0, z))
+    }
+}
+
+/// SplitMix64, used to derive everything random from a seed.
+pub(crate) fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Seeded shuffle of `0..256`, repeated so lookups can add an offset
+/// without wrapping.
+#[derive(Clone)]
+struct Permutation([u8; 512]);
+
+impl Permutation {
+    fn new(seed: u64) -> Self {
+        let mut values = [0u8; 256];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = i as u8;
+        }
+
+        let mut state = seed;
+        for i in (1..256).rev() {
+            let j = (split_mix(&mut state) % (i as u64 + 1)) as usize;
+            values.swap(i, j);
+        }
+
+        let mut table = [0u8; 512];
+        for (i, value) in table.iter_mut().enumerate() {
+            *value = values[i & 255];
+        }
+        Self(table)
+    }
+
+    fn hash(&self, x: i64, y: i64, z: i64) -> usize {
+        let x = self.0[(x & 255) as usize] as usize;
+        let y = self.0[x + (y & 255) as usize] as usize;
+        self.0[y + (z & 255) as usize] as usize
+    }
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + t * (b - a)
+}
+
+/// Interpolates the values at the eight corners of a lattice cell, indexed
+/// by `x | y << 1 | z << 2`.
+fn trilinear(corners: [f64; 8], t: DVec3) -> f64 {
+    let x0 = lerp(corners[0], corners[1], t.x);
+    let x1 = lerp(corners[2], corners[3], t.x);
+    let x2 = lerp(corners[4], corners[5], t.x);
+    let x3 = lerp(corners[6], corners[7], t.x);
+    lerp(lerp(x0, x1, t.y), lerp(x2, x3, t.y), t.z)
+}
+
+/// Splits `point` into its lattice cell and the position within it.
+fn cell(point: DVec3) -> ([i64; 3], DVec3) {
+    let floor = point.floor();
+    (
+        [floor.x as i64, floor.y as i64, floor.z as i64],
+        point - floor,
+    )
+}
+
+/// The twelve cube edge directions of improved Perlin noise, with four
+/// repeated to pick one from the low 4 bits of a hash.
+const GRADIENTS: [DVec3; 16] = [
+    DVec3::new(1.0, 1.0, 0.0),
+    DVec3::new(-1.0, 1.0, 0.0),
+    DVec3::new(1.0, -1.0, 0.0),
+    DVec3::new(-1.0, -1.0, 0.0),
+    DVec3::new(1.0, 0.0, 1.0),
+    DVec3::new(-1.0, 0.0, 1.0),
+    DVec3::new(1.0, 0.0, -1.0),
+    DVec3::new(-1.0, 0.0, -1.0),
+    DVec3::new(0.0, 1.0, 1.0),
+    DVec3::new(0.0, -1.0, 1.0),
+    DVec3::new(0.0, 1.0, -1.0),
+    DVec3::new(0.0, -1.0, -1.0),
+    DVec3::new(1.0, 1.0, 0.0),
+    DVec3::new(0.0, -1.0, 1.0),
+    DVec3::new(-1.0, 1.0, 0.0),
+    DVec3::new(0.0, -1.0, -1.0),
+];
+
+fn gradient(hash: usize, offset: DVec3) -> f64 {
+    GRADIENTS[hash & 15].dot(offset)
+}
+
+/// Improved Perlin gradient noise with a unit lattice.
+#[derive(Clone)]
+pub struct Perlin {
+    permutation: Permutation,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            permutation: Permutation::new(seed),
+        }
+    }
+}
+
+impl Noise for Perlin {
+    fn get(&self, point: DVec3) -> f64 {
+        let ([x, y, z], t) = cell(point);
+        let corners = std::array::from_fn(|i| {
+            let corner = DVec3::new((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64);
+            let hash = self.permutation.hash(
+                x + corner.x as i64,
+                y + corner.y as i64,
+                z + corner.z as i64,
+            );
+            gradient(hash, t - corner)
+        });
+        trilinear(corners, DVec3::new(fade(t.x), fade(t.y), fade(t.z)))
+    }
+}
+
+/// Simplex gradient noise, with fewer directional artifacts than
+/// [`Perlin`].
+#[derive(Clone)]
+pub struct Simplex {
+    permutation: Permutation,
+}
+
+impl Simplex {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            permutation: Permutation::new(seed),
+        }
+    }
+}
+
+impl Noise for Simplex {
+    fn get(&self, point: DVec3) -> f64 {
+        const SKEW: f64 = 1.0 / 3.0;
+        const UNSKEW: f64 = 1.0 / 6.0;
+
+        // Find the simplex cell containing the point in skewed space.
+        let skewed = point + DVec3::splat((point.x + point.y + point.z) * SKEW);
+        let ([i, j, k], _) = cell(skewed);
+        let origin = DVec3::new(i as f64, j as f64, k as f64);
+        let d0 = point - (origin - DVec3::splat((origin.x + origin.y + origin.z) * UNSKEW));
+
+        // Walk to the far corner along the axes in decreasing order of `d0`.
+        let (step1, step2) = if d0.x >= d0.y {
+            if d0.y >= d0.z {
+                ([1, 0, 0], [1, 1, 0])
+            } else if d0.x >= d0.z {
+                ([1, 0, 0], [1, 0, 1])
+            } else {
+                ([0, 0, 1], [1, 0, 1])
+            }
+        } else if d0.y < d0.z {
+            ([0, 0, 1], [0, 1, 1])
+        } else if d0.x < d0.z {
+            ([0, 1, 0], [0, 1, 1])
+        } else {
+            ([0, 1, 0], [1, 1, 0])
+        };
+
+        let corners = [[0, 0, 0], step1, step2, [1, 1, 1]];
+        let mut sum = 0.0;
+        for (n, [ci, cj, ck]) in corners.into_iter().enumerate() {
+            let offset = d0
+                - DVec3::new(ci as f64, cj as f64, ck as f64)
+                + DVec3::splat(n as f64 * UNSKEW);
+            let falloff = 0.6 - offset.length_squared();
+            if falloff > 0.0 {
+                let hash = self.permutation.hash(i + ci, j + cj, k + ck);
+                let falloff = falloff * falloff;
+                sum += falloff * falloff * gradient(hash, offset);
+            }
+        }
+
+        32.0 * sum
+    }
+}
+
+/// Smoothly interpolated random values on a unit lattice. Cheaper but
+/// blockier than gradient noise.
+#[derive(Clone)]
+pub struct Value {
+    permutation: Permutation,
+}
+
+impl Value {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            permutation: Permutation::new(seed),
+        }
+    }
+}
+
+impl Noise for Value {
+    fn get(&self, point: DVec3) -> f64 {
+        let ([x, y, z], t) = cell(point);
+        let corners = std::array::from_fn(|i| {
+            let hash = self.permutation.hash(
+                x + (i & 1) as i64,
+                y + ((i >> 1) & 1) as i64,
+                z + (i >> 2) as i64,
+            );
+            hash as f64 / 127.5 - 1.0
+        });
+        trilinear(corners, DVec3::new(fade(t.x), fade(t.y), fade(t.z)))
+    }
+}
+
+/// Shifts every octave so they do not all share the lattice origin.
+fn octave_offset(octave: u32) -> DVec3 {
+    DVec3::new(17.13, 31.71, 47.29) * octave as f64
+}
+
+/// Fractal Brownian motion: `octaves` layers of `source`, each at
+/// `lacunarity` times the frequency and `persistence` times the amplitude
+/// of the previous one.
+#[derive(Clone)]
+pub struct Fbm<N> {
+    pub source: N,
+    pub octaves: u32,
+    /// Frequency of the first octave, in cycles per block.
+    pub frequency: f64,
+    pub lacunarity: f64,
+    pub persistence: f64,
+}
+
+impl<N> Fbm<N> {
+    pub fn new(source: N) -> Self {
+        Self {
+            source,
+            octaves: 4,
+            frequency: 1.0 / 64.0,
+            lacunarity: 2.0,
+            persistence: 0.5,
+        }
+    }
+
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    pub fn with_frequency(mut self, frequency: f64) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn with_lacunarity(mut self, lacunarity: f64) -> Self {
+        self.lacunarity = lacunarity;
+        self
+    }
+
+    pub fn with_persistence(mut self, persistence: f64) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    /// Sums `octave` over the octaves, normalized by the total amplitude.
+    fn sum(&self, point: DVec3, octave: impl Fn(DVec3) -> f64) -> f64 {
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = self.frequency;
+        for i in 0..self.octaves {
+            sum += octave(point * frequency + octave_offset(i)) * amplitude;
+            total += amplitude;
+            amplitude *= self.persistence;
+            frequency *= self.lacunarity;
+        }
+        if total > 0.0 { sum / total } else { 0.0 }
+    }
+}
+
+impl<N: Noise> Noise for Fbm<N> {
+    fn get(&self, point: DVec3) -> f64 {
+        self.sum(point, |point| self.source.get(point))
+    }
+}
+
+/// [`Fbm`] of the folded source, `1 - |n|` squared, which turns its zero
+/// crossings into sharp ridges such as mountain chains.
+#[derive(Clone)]
+pub struct Ridged<N>(pub Fbm<N>);
+
+impl<N> Ridged<N> {
+    pub fn new(source: N) -> Self {
+        Self(Fbm::new(source))
+    }
+}
+
+impl<N: Noise> Noise for Ridged<N> {
+    fn get(&self, point: DVec3) -> f64 {
+        let ridges = self.0.sum(point, |point| {
+            let ridge = 1.0 - self.0.source.get(point).abs();
+            ridge * ridge
+        });
+        ridges * 2.0 - 1.0
+    }
+}
+
+/// Samples `source` at a point displaced by `warp`, bending its features
+/// into more natural shapes.
+#[derive(Clone)]
+pub struct DomainWarp<N, W> {
+    pub source: N,
+    pub warp: W,
+    /// Largest displacement, in the units `source` is sampled in.
+    pub strength: f64,
+}
+
+impl<N, W> DomainWarp<N, W> {
+    pub fn new(source: N, warp: W, strength: f64) -> Self {
+        Self {
+            source,
+            warp,
+            strength,
+        }
+    }
+}
+
+impl<N: Noise, W: Noise> Noise for DomainWarp<N, W> {
+    fn get(&self, point: DVec3) -> f64 {
+        // Decorrelates the displacement along each axis.
+        let displacement = DVec3::new(
+            self.warp.get(point),
+            self.warp.get(point + DVec3::new(5.2, 1.3, 9.7)),
+            self.warp.get(point + DVec3::new(1.7, 9.2, 3.4)),
+        );
+        self.source.get(point + displacement * self.strength)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scattered points, negative coordinates and lattice points included.
+    fn points() -> impl Iterator<Item = DVec3> {
+        (0..4096).map(|i| {
+            let i = i as f64;
+            DVec3::new(i * 0.37 - 700.0, (i * 0.11) % 23.0 - 11.0, (i * 1.73) % 97.0 - 48.5)
+        })
+    }
+
+    fn noises(seed: u64) -> Vec<(&'static str, Box<dyn Noise>)> {
+        vec![
+            ("perlin", Box::new(Perlin::new(seed))),
+            ("simplex", Box::new(Simplex::new(seed))),
+            ("value", Box::new(Value::new(seed))),
+            ("fbm", Box::new(Fbm::new(Perlin::new(seed)).with_frequency(0.1))),
+            ("ridged", Box::new(Ridged(Fbm::new(Simplex::new(seed)).with_frequency(0.1)))),
+            ("warp", Box::new(DomainWarp::new(Perlin::new(seed), Simplex::new(seed ^ 1), 2.0))),
+        ]
+    }
+
+    #[test]
+    fn same_seed_is_bit_identical() {
+        for ((name, a), (_, b)) in noises(42).into_iter().zip(noises(42)) {
+            for point in points() {
+                assert_eq!(a.get(point).to_bits(), b.get(point).to_bits(), "{name} at {point}");
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_differ() {
+        for ((name, a), (_, b)) in noises(1).into_iter().zip(noises(2)) {
+            assert!(points().any(|point| a.get(point) != b.get(point)), "{name}");
+        }
+    }
+
+    #[test]
+    fn stays_in_range() {
+        for (name, noise) in noises(7) {
+            let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+            for point in points() {
+                let value = noise.get(point);
+                assert!((-1.0..=1.0).contains(&value), "{name} is {value} at {point}");
+                min = min.min(value);
+                max = max.max(value);
+            }
+            // Uses a good part of the range rather than being flat.
+            assert!(max - min > 0.5, "{name} spans {min}..{max}");
+        }
+    }
+}
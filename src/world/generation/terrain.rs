@@ -0,0 +1,132 @@
+use bevy::math::I64Vec3;
+
+use crate::world::{block::BlockState, chunk::index_to_pos};
+
+use super::{
+    noise::{Fbm, Noise, Perlin},
+    WorldGenerator,
+};
+
+/// Solid ground up to a height sampled from 2D noise, air above.
+#[derive(Clone)]
+pub struct HeightmapTerrain<N = Fbm<Perlin>> {
+    pub seed: u64,
+    pub noise: N,
+    /// Height of the surface where the noise is zero.
+    pub base_height: f64,
+    /// Distance the surface rises and falls around `base_height`.
+    pub amplitude: f64,
+    pub block: BlockState,
+}
+
+impl HeightmapTerrain {
+    /// Rolling hills of stone from five octaves of Perlin noise.
+    pub fn new(seed: u64) -> Self {
+        Self::with_noise(
+            seed,
+            Fbm::new(Perlin::new(seed))
+                .with_octaves(5)
+                .with_frequency(1.0 / 128.0),
+        )
+    }
+}
+
+impl<N: Noise> HeightmapTerrain<N> {
+    pub fn with_noise(seed: u64, noise: N) -> Self {
+        Self {
+            seed,
+            noise,
+            base_height: 0.0,
+            amplitude: 32.0,
+            block: BlockState::STONE,
+        }
+    }
+
+    /// Height of the first air block in the column at `x`, `z`.
+    pub fn height(&self, x: i64, z: i64) -> i64 {
+        (self.base_height + self.amplitude * self.noise.get_2d(x as f64, z as f64)).floor() as i64
+    }
+
+    fn block_below(&self, y: i64, height: i64) -> BlockState {
+        if y < height {
+            self.block
+        } else {
+            BlockState::AIR
+        }
+    }
+}
+
+impl<N: Noise> WorldGenerator for HeightmapTerrain<N> {
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn block(&self, pos: I64Vec3) -> BlockState {
+        self.block_below(pos.y, self.height(pos.x, pos.z))
+    }
+
+    /// Samples the noise once per column rather than once per block.
+    fn generate_chunk(&self, origin: I64Vec3, size: usize, blocks: &mut [BlockState]) {
+        let heights = (0..size * size)
+            .map(|i| self.height(origin.x + (i / size) as i64, origin.z + (i % size) as i64))
+            .collect::<Vec<_>>();
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let pos = index_to_pos(i, size);
+            let height = heights[pos.x as usize * size + pos.z as usize];
+            *block = self.block_below(origin.y + pos.y as i64, height);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: I64Vec3 = I64Vec3::new(-16, 0, 32);
+
+    fn chunk(terrain: &HeightmapTerrain) -> Vec<BlockState> {
+        let mut blocks = vec![BlockState::AIR; 16 * 16 * 16];
+        terrain.generate_chunk(ORIGIN, 16, &mut blocks);
+        blocks
+    }
+
+    /// Changing any of these changes every world generated from a seed.
+    #[test]
+    fn heights_are_pinned() {
+        let terrain = HeightmapTerrain::new(42);
+        let heights = [(0, 0), (15, 0), (0, 15), (-100, 37), (1234, -5678)]
+            .map(|(x, z)| terrain.height(x, z));
+        assert_eq!(heights, [2, 3, 6, -3, -7]);
+    }
+
+    #[test]
+    fn chunk_is_pinned() {
+        let blocks = chunk(&HeightmapTerrain::new(42));
+        let solid = blocks.iter().filter(|block| **block != BlockState::AIR).count();
+        assert_eq!(solid, 2780);
+
+        let row = (0..16)
+            .map(|x| (0..16).filter(|y| blocks[x * 256 + y * 16 + 5] != BlockState::AIR).count())
+            .collect::<Vec<_>>();
+        assert_eq!(row, [10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11]);
+    }
+
+    #[test]
+    fn same_seed_is_identical() {
+        assert_eq!(chunk(&HeightmapTerrain::new(7)), chunk(&HeightmapTerrain::new(7)));
+        let heights = |terrain: HeightmapTerrain| {
+            (0..64).map(|i| terrain.height(i * 37, -i * 53)).collect::<Vec<_>>()
+        };
+        assert_ne!(heights(HeightmapTerrain::new(7)), heights(HeightmapTerrain::new(8)));
+    }
+
+    #[test]
+    fn chunk_matches_blocks() {
+        let terrain = HeightmapTerrain::new(42);
+        for (i, block) in chunk(&terrain).into_iter().enumerate() {
+            let pos = ORIGIN + index_to_pos(i, 16).as_i64vec3();
+            assert_eq!(block, terrain.block(pos), "at {pos}");
+        }
+    }
+}
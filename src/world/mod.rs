@@ -1,20 +1,209 @@
 pub mod block;
 pub mod chunk;
 pub mod generation;
+pub mod palette;
+pub mod streaming;
 
-use bevy::{math::I64Vec3, prelude::*};
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::{
+    ecs::{component::HookContext, system::SystemParam, world::DeferredWorld},
+    math::I64Vec3,
+    prelude::*,
+    tasks::{futures::check_ready, AsyncComputeTaskPool, Task},
+};
+use block::BlockState;
 use chunk::Chunk;
-use generation::BlockGenerator;
+use generation::{
+    biome::Biome,
+    features::{
+        apply_feature_writes, place_features, replay_feature_writes, FeaturePlacement, FeatureWrite,
+    },
+    WorldGenerator,
+};
 
 #[derive(Component)]
-#[require(Visibility, Transform)]
+#[require(Visibility, Transform, ChunkMap, FeatureWrites)]
 pub struct Level {
-    pub generator: BlockGenerator,
+    pub generator: Arc<dyn WorldGenerator>,
+    /// Size of the chunks the level is made of, one of those simulated by
+    /// [`VoxelPlugin`](crate::VoxelPlugin).
+    pub chunk_size: usize,
+    /// Features placed on each chunk once its terrain is generated, in
+    /// order.
+    pub features: Vec<FeaturePlacement>,
 }
 
+/// Chunk coordinate of a chunk entity. Spawned as a child of its [`Level`],
+/// the chunk is registered in the level's [`ChunkMap`].
 #[derive(Component)]
+#[component(on_insert = insert_into_chunk_map, on_replace = remove_from_chunk_map)]
 pub struct ChunkOffset(I64Vec3);
 
+impl ChunkOffset {
+    pub fn get(&self) -> I64Vec3 {
+        self.0
+    }
+}
+
+/// Chunk entities of a [`Level`] by chunk coordinate, kept up to date as
+/// its chunks are spawned and despawned, including those still
+/// [generating](GeneratingChunk). A level holds chunks of one size.
+#[derive(Component, Debug, Default)]
+pub struct ChunkMap {
+    chunks: HashMap<I64Vec3, Entity>,
+}
+
+impl ChunkMap {
+    pub fn get(&self, chunk: I64Vec3) -> Option<Entity> {
+        self.chunks.get(&chunk).copied()
+    }
+
+    pub fn contains(&self, chunk: I64Vec3) -> bool {
+        self.chunks.contains_key(&chunk)
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (I64Vec3, Entity)> + '_ {
+        self.chunks.iter().map(|(chunk, entity)| (*chunk, *entity))
+    }
+}
+
+/// Blocks of features placed on the loaded chunks of a [`Level`], by the
+/// chunk they fall in and then the chunk that placed them.
+///
+/// A chunk gets the writes into it applied in the order of the chunks
+/// placing them, whether they arrive before it finishes generating or after,
+/// so overlapping features come out the same however the world is explored.
+/// Writes are kept while either chunk is loaded, so a chunk generated again
+/// gets its neighbours' features back.
+#[derive(Component, Debug, Default)]
+pub struct FeatureWrites {
+    writes: HashMap<I64Vec3, HashMap<I64Vec3, Vec<FeatureWrite>>>,
+}
+
+impl FeatureWrites {
+    /// Writes queued for `chunk`, ordered by the chunk placing them.
+    pub fn get(&self, chunk: I64Vec3) -> Vec<FeatureWrite> {
+        self.get_without(chunk, &[])
+    }
+
+    /// Writes queued for `chunk` except those placed by `excluded`, ordered
+    /// by the chunk placing them.
+    pub fn get_without(&self, chunk: I64Vec3, excluded: &[I64Vec3]) -> Vec<FeatureWrite> {
+        let Some(by_source) = self.writes.get(&chunk) else {
+            return Vec::new();
+        };
+        let mut sources = by_source
+            .keys()
+            .copied()
+            .filter(|source| !excluded.contains(source))
+            .collect::<Vec<_>>();
+        sources.sort_unstable_by_key(|source| source.to_array());
+        sources
+            .iter()
+            .flat_map(|source| by_source[source].iter().copied())
+            .collect()
+    }
+
+    /// Queues `writes` into `target` placed by `source`, replacing those
+    /// from an earlier generation of `source`. Returns whether they differ
+    /// from those already queued.
+    pub fn insert(&mut self, target: I64Vec3, source: I64Vec3, writes: Vec<FeatureWrite>) -> bool {
+        let by_source = self.writes.entry(target).or_default();
+        if by_source.get(&source) == Some(&writes) {
+            return false;
+        }
+        by_source.insert(source, writes);
+        true
+    }
+
+    /// Forgets the writes between chunks that are both unloaded from `map`.
+    pub fn retain_loaded(&mut self, map: &ChunkMap) {
+        self.writes.retain(|target, by_source| {
+            let target_loaded = map.contains(*target);
+            by_source.retain(|source, _| target_loaded || map.contains(*source));
+            !by_source.is_empty()
+        });
+    }
+}
+
+pub(crate) fn prune_feature_writes(mut levels: Query<(&ChunkMap, &mut FeatureWrites), Changed<ChunkMap>>) {
+    for (map, mut writes) in &mut levels {
+        writes.retain_loaded(map);
+    }
+}
+
+fn insert_into_chunk_map(mut world: DeferredWorld, HookContext { entity, .. }: HookContext) {
+    let Some(level) = world.get::<ChildOf>(entity).map(ChildOf::parent) else {
+        return;
+    };
+    let chunk = world.get::<ChunkOffset>(entity).unwrap().0;
+    if let Some(mut map) = world.get_mut::<ChunkMap>(level) {
+        map.chunks.insert(chunk, entity);
+    }
+}
+
+fn remove_from_chunk_map(mut world: DeferredWorld, HookContext { entity, .. }: HookContext) {
+    let Some(level) = world.get::<ChildOf>(entity).map(ChildOf::parent) else {
+        return;
+    };
+    let chunk = world.get::<ChunkOffset>(entity).unwrap().0;
+    if let Some(mut map) = world.get_mut::<ChunkMap>(level) {
+        // Another chunk may have taken the coordinate over since.
+        if map.chunks.get(&chunk) == Some(&entity) {
+            map.chunks.remove(&chunk);
+        }
+    }
+}
+
+/// Splits a world block position into the coordinate of the chunk of
+/// `SIZE` containing it and the position within that chunk. Rounds towards
+/// negative infinity, so `-1` is at `SIZE - 1` in chunk `-1`.
+pub fn split_world_pos<const SIZE: usize>(pos: I64Vec3) -> (I64Vec3, IVec3) {
+    let size = I64Vec3::splat(SIZE as i64);
+    (pos.div_euclid(size), pos.rem_euclid(size).as_ivec3())
+}
+
+/// Reads and writes the blocks of levels made of chunks of `SIZE` by world
+/// position.
+#[derive(SystemParam)]
+pub struct LevelBlocks<'w, 's, const SIZE: usize> {
+    levels: Query<'w, 's, &'static ChunkMap>,
+    chunks: Query<'w, 's, &'static mut Chunk<SIZE>>,
+}
+
+impl<const SIZE: usize> LevelBlocks<'_, '_, SIZE> {
+    /// Returns the block at `pos` in `level`, or `None` if its chunk is not
+    /// loaded.
+    pub fn get_block(&self, level: Entity, pos: I64Vec3) -> Option<BlockState> {
+        let (chunk, local) = split_world_pos::<SIZE>(pos);
+        let entity = self.levels.get(level).ok()?.get(chunk)?;
+        Some(self.chunks.get(entity).ok()?[local])
+    }
+
+    /// Writes `state` at `pos` in `level`, returning the block previously
+    /// there, or `None` without writing if its chunk is not loaded.
+    pub fn set_block(&mut self, level: Entity, pos: I64Vec3, state: BlockState) -> Option<BlockState> {
+        let (chunk, local) = split_world_pos::<SIZE>(pos);
+        let entity = self.levels.get(level).ok()?.get(chunk)?;
+        let mut chunk = self.chunks.get_mut(entity).ok()?;
+        let previous = chunk[local];
+        // Leave the chunk untouched so it is not re-meshed for nothing.
+        if previous != state {
+            chunk.set_at(local, state);
+        }
+        Some(previous)
+    }
+}
+
 pub(crate) fn propagate_chunk_offsets<const SIZE: usize>(
     mut chunks: Query<(&Chunk<SIZE>, &ChunkOffset, &mut Transform)>
 ) {
@@ -24,21 +213,262 @@ pub(crate) fn propagate_chunk_offsets<const SIZE: usize>(
 }
 
 impl Level {
+    /// A level of 16³ chunks filled by `generator`.
+    pub fn new(generator: impl WorldGenerator) -> Self {
+        Self {
+            generator: Arc::new(generator),
+            chunk_size: 16,
+            features: Vec::new(),
+        }
+    }
+
+    pub fn with_feature(mut self, feature: FeaturePlacement) -> Self {
+        self.features.push(feature);
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Biome of the column containing `pos`, if the generator has biomes.
+    pub fn biome_at(&self, pos: I64Vec3) -> Option<&Biome> {
+        self.generator.biome_at(pos)
+    }
+
+    /// Generates the chunk right away with its own features. Parts of
+    /// features reaching in from or out to other chunks are left out,
+    /// [`Load`] queues them instead.
     pub fn load<const SIZE: usize>(&self, chunk: I64Vec3) -> (Chunk<SIZE>, ChunkOffset) {
-        (
-            Chunk::<SIZE>::generate(self.generator, chunk * SIZE as i64),
-            ChunkOffset(chunk),
-        )
+        let (mut blocks, writes) = generate_chunk(self.generator.as_ref(), &self.features, chunk);
+        if let Some(own) = writes.get(&chunk) {
+            apply_feature_writes(&mut blocks, chunk, own);
+        }
+        (blocks, ChunkOffset(chunk))
+    }
+}
+
+/// Generates the terrain of `chunk` and places its features, returning the
+/// blocks of the features by the chunk they fall in, `chunk` included.
+fn generate_chunk<const SIZE: usize>(
+    generator: &dyn WorldGenerator,
+    features: &[FeaturePlacement],
+    chunk: I64Vec3,
+) -> (Chunk<SIZE>, HashMap<I64Vec3, Vec<FeatureWrite>>) {
+    let blocks = Chunk::<SIZE>::generate(generator, chunk * SIZE as i64);
+    let writes = place_features(generator, features, chunk, &blocks);
+    (blocks, writes)
+}
+
+/// Chunk of `SIZE` being generated on the [`AsyncComputeTaskPool`]. The
+/// [`Chunk`] is inserted in its place once done; despawning the entity
+/// first cancels the generation.
+#[derive(Component)]
+pub struct GeneratingChunk<const SIZE: usize>(
+    Task<(Chunk<SIZE>, HashMap<I64Vec3, Vec<FeatureWrite>>)>,
+);
+
+pub(crate) fn finish_chunk_generation<const SIZE: usize>(
+    mut commands: Commands,
+    mut generating: Query<(Entity, &ChunkOffset, &ChildOf, &mut GeneratingChunk<SIZE>)>,
+    mut levels: Query<(&Level, &ChunkMap, &mut FeatureWrites)>,
+    mut chunks: Query<&mut Chunk<SIZE>>,
+) {
+    let mut finished = Vec::new();
+    for (entity, offset, child_of, mut task) in &mut generating {
+        let Some((chunk, writes)) = check_ready(&mut task.0) else {
+            continue;
+        };
+        finished.push((entity, offset.0, child_of.parent(), chunk, writes));
+    }
+
+    // Queue every write first so chunks finishing together see each other's.
+    let mut updated = HashMap::<_, (Vec<_>, Vec<_>)>::new();
+    for (_, source, level, _, writes) in &mut finished {
+        let Ok((_, _, mut queued)) = levels.get_mut(*level) else {
+            continue;
+        };
+        for (target, writes) in writes.drain() {
+            let positions = writes.iter().map(|write| write.pos).collect::<Vec<_>>();
+            if queued.insert(target, *source, writes) {
+                let (sources, all_positions) = updated.entry((*level, target)).or_default();
+                sources.push(*source);
+                all_positions.extend(positions);
+            }
+        }
+    }
+
+    for (entity, chunk_pos, level, mut chunk, _) in finished {
+        if let Ok((_, _, queued)) = levels.get(level) {
+            apply_feature_writes(&mut chunk, chunk_pos, &queued.get(chunk_pos));
+        }
+
+        // The chunk may have been unloaded meanwhile.
+        commands
+            .entity(entity)
+            .try_remove::<GeneratingChunk<SIZE>>()
+            .try_insert(chunk);
+    }
+
+    // Chunks loaded earlier get the new writes replayed in order with those
+    // they already have, keeping blocks edited since.
+    for ((level, target), (sources, positions)) in updated {
+        let Ok((level, map, queued)) = levels.get(level) else {
+            continue;
+        };
+        let Some(mut chunk) = map.get(target).and_then(|entity| chunks.get_mut(entity).ok()) else {
+            continue;
+        };
+        replay_feature_writes(
+            &mut chunk,
+            target,
+            level.generator.as_ref(),
+            &queued.get_without(target, &sources),
+            &queued.get(target),
+            &positions,
+        );
     }
 }
 
+/// Starts loading chunks into a level.
 pub trait Load {
     fn load<const SIZE: usize>(&mut self, chunk: I64Vec3, commands: &mut Commands);
 }
 
+/// Spawns the chunk under the level right away and generates its blocks in
+/// the background, see [`GeneratingChunk`].
 impl Load for (Entity, &Level) {
     fn load<const SIZE: usize>(&mut self, chunk: I64Vec3, commands: &mut Commands) {
-        let _chunk = commands.spawn((self.1.load::<SIZE>(chunk), Visibility::Visible)).id();
-        // commands.entity(self.0).add_child(chunk);
+        let generator = self.1.generator.clone();
+        let features = self.1.features.clone();
+        let task = AsyncComputeTaskPool::get()
+            .spawn(async move { generate_chunk::<SIZE>(generator.as_ref(), &features, chunk) });
+        commands.spawn((ChunkOffset(chunk), GeneratingChunk::<SIZE>(task), ChildOf(self.0)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::ecs::system::RunSystemOnce;
+    use generation::{
+        features::{Feature, FeatureRng, FeatureWriter, Tree},
+        flat,
+    };
+
+    use super::*;
+    use crate::VoxelPlugin;
+
+    /// A tree on the last column along `x` of its chunk, so its leaves reach
+    /// into the next chunk.
+    struct BorderTree;
+
+    impl Feature for BorderTree {
+        fn place(&self, origin: I64Vec3, rng: &mut FeatureRng, writer: &mut FeatureWriter) {
+            let x = origin.x.div_euclid(16) * 16 + 15;
+            Tree::default().place(I64Vec3::new(x, origin.y, origin.z), rng, writer);
+        }
+    }
+
+    /// Chunk the trees stand on, and the one their leaves reach into.
+    const SOURCE: I64Vec3 = I64Vec3::new(0, -1, 0);
+    const TARGET: I64Vec3 = I64Vec3::new(1, 0, 0);
+
+    fn level_app() -> (App, Entity) {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, VoxelPlugin::default()));
+        let level = Level::new(flat::<0>).with_feature(FeaturePlacement::new(BorderTree, 1));
+        let level = app.world_mut().spawn(level).id();
+        (app, level)
+    }
+
+    /// Loads `chunk` and updates until it is generated.
+    fn load(app: &mut App, level: Entity, chunk: I64Vec3) -> Entity {
+        app.world_mut()
+            .run_system_once(move |mut commands: Commands, levels: Query<&Level>| {
+                (level, levels.get(level).unwrap()).load::<16>(chunk, &mut commands);
+            })
+            .unwrap();
+        for _ in 0..1000 {
+            app.update();
+            let entity = app.world().get::<ChunkMap>(level).unwrap().get(chunk).unwrap();
+            if app.world().get::<Chunk<16>>(entity).is_some() {
+                return entity;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("chunk {chunk} was not generated");
+    }
+
+    #[test]
+    fn split_world_pos_rounds_down() {
+        assert_eq!(
+            split_world_pos::<16>(I64Vec3::new(17, 0, 15)),
+            (I64Vec3::new(1, 0, 0), IVec3::new(1, 0, 15))
+        );
+        assert_eq!(
+            split_world_pos::<16>(I64Vec3::new(-1, -16, -17)),
+            (I64Vec3::new(-1, -1, -2), IVec3::new(15, 0, 15))
+        );
+    }
+
+    fn blocks(app: &App, entity: Entity) -> Vec<BlockState> {
+        app.world().get::<Chunk<16>>(entity).unwrap().into_iter().copied().collect()
+    }
+
+    #[test]
+    fn features_reach_into_neighbours_loaded_before_or_after() {
+        let (mut app, level) = level_app();
+        load(&mut app, level, SOURCE);
+        let target = load(&mut app, level, TARGET);
+        let loaded_after = blocks(&app, target);
+
+        let (mut app, level) = level_app();
+        let target = load(&mut app, level, TARGET);
+        load(&mut app, level, SOURCE);
+        let loaded_before = blocks(&app, target);
+
+        assert!(loaded_after.contains(&BlockState::LEAVES));
+        assert_eq!(loaded_after, loaded_before);
+    }
+
+    #[test]
+    fn writes_are_pruned_once_both_chunks_unload() {
+        let (mut app, level) = level_app();
+        let source = load(&mut app, level, SOURCE);
+        let target = load(&mut app, level, TARGET);
+        let queued = |app: &App| app.world().get::<FeatureWrites>(level).unwrap().get(TARGET);
+        assert!(!queued(&app).is_empty());
+
+        app.world_mut().despawn(target);
+        app.update();
+        assert!(!queued(&app).is_empty());
+
+        app.world_mut().despawn(source);
+        app.update();
+        assert!(app.world().get::<FeatureWrites>(level).unwrap().writes.is_empty());
+    }
+
+    #[test]
+    fn replay_keeps_edited_blocks() {
+        let (mut app, level) = level_app();
+        let target = load(&mut app, level, TARGET);
+        app.world_mut()
+            .run_system_once(move |mut blocks: LevelBlocks<16>| {
+                for y in 0..16 {
+                    for z in 0..16 {
+                        blocks.set_block(level, I64Vec3::new(16, y, z), BlockState::DIRT);
+                    }
+                }
+            })
+            .unwrap();
+        load(&mut app, level, SOURCE);
+
+        let chunk = app.world().get::<Chunk<16>>(target).unwrap();
+        let column = |x| (0..16).flat_map(move |y| (0..16).map(move |z| IVec3::new(x, y, z)));
+        assert!(column(0).all(|pos| chunk[pos] == BlockState::DIRT));
+        assert!(column(1).any(|pos| chunk[pos] == BlockState::LEAVES));
     }
 }
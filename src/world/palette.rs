@@ -0,0 +1,361 @@
+use std::iter::FusedIterator;
+
+/// Fixed-length storage that keeps every distinct value once in a palette
+/// and stores per-entry palette indices bit-packed into `u64` words.
+///
+/// A container holding a single value uses no index data at all. The index
+/// width grows automatically as new values are added to the palette, after
+/// first reclaiming the palette entries no longer used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PalettedContainer<T> {
+    len: usize,
+    bits: u32,
+    palette: Vec<T>,
+    data: Vec<u64>,
+}
+
+impl<T: Copy + Eq> PalettedContainer<T> {
+    /// Creates a container of `len` entries, all set to `value`.
+    pub fn new(len: usize, value: T) -> Self {
+        Self {
+            len,
+            bits: 0,
+            palette: vec![value],
+            data: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bits used to store each palette index, `0` when uniform.
+    pub fn bits_per_entry(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn palette(&self) -> &[T] {
+        &self.palette
+    }
+
+    /// Returns the value shared by every entry, if there is exactly one.
+    pub fn as_uniform(&self) -> Option<&T> {
+        if self.bits == 0 {
+            self.palette.first()
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &T {
+        assert!(
+            index < self.len,
+            "index {index} out of bounds for container of length {}",
+            self.len
+        );
+        &self.palette[self.palette_index(index)]
+    }
+
+    /// Writes `value` at `index`, returning the value previously stored there.
+    pub fn set(&mut self, index: usize, value: T) -> T {
+        let previous = *self.get(index);
+        if previous == value {
+            return previous;
+        }
+
+        let palette_index = match self.palette.iter().position(|entry| *entry == value) {
+            Some(palette_index) => palette_index,
+            None => {
+                // Drop unused values rather than widening the indices.
+                if bits_for(self.palette.len() + 1) > self.bits {
+                    self.compact();
+                }
+                self.palette.push(value);
+                let required = bits_for(self.palette.len());
+                if required > self.bits {
+                    self.repack(required);
+                }
+                self.palette.len() - 1
+            }
+        };
+
+        self.write_index(index, palette_index);
+        previous
+    }
+
+    /// Sets every entry to `value`, dropping the palette and index data.
+    pub fn fill(&mut self, value: T) {
+        self.bits = 0;
+        self.palette.clear();
+        self.palette.push(value);
+        self.data = Vec::new();
+    }
+
+    /// Removes unused palette entries and shrinks the index width to fit.
+    pub fn compact(&mut self) {
+        if self.bits == 0 {
+            return;
+        }
+
+        let mut used = vec![false; self.palette.len()];
+        for index in 0..self.len {
+            used[self.palette_index(index)] = true;
+        }
+        if used.iter().all(|used| *used) {
+            return;
+        }
+
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::with_capacity(self.palette.len());
+        for (old, value) in self.palette.iter().enumerate() {
+            if used[old] {
+                remap[old] = palette.len();
+                palette.push(*value);
+            }
+        }
+
+        if palette.len() == 1 {
+            self.fill(palette[0]);
+            return;
+        }
+
+        let indices = (0..self.len)
+            .map(|index| remap[self.palette_index(index)])
+            .collect::<Vec<_>>();
+
+        self.palette = palette;
+        self.bits = bits_for(self.palette.len());
+        self.data = vec![0; words_for(self.len, self.bits)];
+        for (index, palette_index) in indices.into_iter().enumerate() {
+            self.write_index(index, palette_index);
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            container: self,
+            front: 0,
+            back: self.len,
+        }
+    }
+
+    fn palette_index(&self, index: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+
+        let per_word = (u64::BITS / self.bits) as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        ((self.data[index / per_word] >> shift) & mask(self.bits)) as usize
+    }
+
+    fn write_index(&mut self, index: usize, palette_index: usize) {
+        let per_word = (u64::BITS / self.bits) as usize;
+        let shift = (index % per_word) as u32 * self.bits;
+        let word = &mut self.data[index / per_word];
+        *word &= !(mask(self.bits) << shift);
+        *word |= (palette_index as u64) << shift;
+    }
+
+    fn repack(&mut self, bits: u32) {
+        let indices = (0..self.len)
+            .map(|index| self.palette_index(index))
+            .collect::<Vec<_>>();
+
+        self.bits = bits;
+        self.data = vec![0; words_for(self.len, bits)];
+        for (index, palette_index) in indices.into_iter().enumerate() {
+            self.write_index(index, palette_index);
+        }
+    }
+}
+
+const fn mask(bits: u32) -> u64 {
+    (1 << bits) - 1
+}
+
+/// Minimum index width able to address `palette_len` entries.
+const fn bits_for(palette_len: usize) -> u32 {
+    if palette_len <= 1 {
+        0
+    } else {
+        usize::BITS - (palette_len - 1).leading_zeros()
+    }
+}
+
+const fn words_for(len: usize, bits: u32) -> usize {
+    let per_word = (u64::BITS / bits) as usize;
+    len.div_ceil(per_word)
+}
+
+pub struct Iter<'a, T> {
+    container: &'a PalettedContainer<T>,
+    front: usize,
+    back: usize,
+}
+
+impl<'a, T: Copy + Eq> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let item = self.container.get(self.front);
+        self.front += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.back - self.front;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T: Copy + Eq> DoubleEndedIterator for Iter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        Some(self.container.get(self.back))
+    }
+}
+
+impl<T: Copy + Eq> ExactSizeIterator for Iter<'_, T> {}
+
+impl<T: Copy + Eq> FusedIterator for Iter<'_, T> {}
+
+impl<'a, T: Copy + Eq> IntoIterator for &'a PalettedContainer<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks `container` against a plain `Vec` of the same values.
+    fn assert_matches(container: &PalettedContainer<u16>, expected: &[u16]) {
+        assert_eq!(container.len(), expected.len());
+        for (index, value) in expected.iter().enumerate() {
+            assert_eq!(container.get(index), value, "at {index}");
+        }
+    }
+
+    #[test]
+    fn uniform() {
+        let mut container = PalettedContainer::new(100, 7u16);
+        assert_eq!(container.as_uniform(), Some(&7));
+        assert_eq!(container.bits_per_entry(), 0);
+
+        assert_eq!(container.set(42, 7), 7);
+        assert_eq!(container.as_uniform(), Some(&7));
+
+        container.set(42, 8);
+        assert_eq!(container.as_uniform(), None);
+        container.fill(9);
+        assert_eq!(container.as_uniform(), Some(&9));
+        assert_matches(&container, &[9; 100]);
+    }
+
+    /// 3 and 5 bits leave padding at the end of each word, and 100 entries
+    /// leave the last word partly used.
+    #[test]
+    fn promotes_through_widths() {
+        let mut container = PalettedContainer::new(100, 0u16);
+        let mut expected = vec![0; 100];
+        let mut widths = Vec::new();
+
+        for value in 1..=16 {
+            // Spread each value over several words.
+            for index in (value as usize..100).step_by(17) {
+                container.set(index, value);
+                expected[index] = value;
+            }
+            widths.push(container.bits_per_entry());
+            assert_matches(&container, &expected);
+        }
+
+        assert_eq!(widths, [1, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4, 5]);
+    }
+
+    #[test]
+    fn compact_collapses_to_uniform() {
+        let mut container = PalettedContainer::new(64, 0u16);
+        for index in 0..5 {
+            container.set(index, index as u16 + 1);
+        }
+        for index in 0..5 {
+            container.set(index, 0);
+        }
+        assert_eq!(container.bits_per_entry(), 3);
+
+        container.compact();
+        assert_eq!(container.as_uniform(), Some(&0));
+        assert_eq!(container.bits_per_entry(), 0);
+        assert_matches(&container, &[0; 64]);
+    }
+
+    #[test]
+    fn compact_shrinks_palette() {
+        let mut container = PalettedContainer::new(70, 0u16);
+        let mut expected = vec![0; 70];
+        for (index, value) in [(3, 1), (30, 2), (31, 3), (69, 4)] {
+            container.set(index, value);
+            expected[index] = value;
+        }
+        container.set(30, 0);
+        container.set(31, 0);
+        expected[30] = 0;
+        expected[31] = 0;
+
+        container.compact();
+        assert_eq!(container.palette(), [0, 1, 4]);
+        assert_eq!(container.bits_per_entry(), 2);
+        assert_matches(&container, &expected);
+    }
+
+    #[test]
+    fn set_reuses_unused_entries() {
+        let mut container = PalettedContainer::new(32, 0u16);
+        // Values come and go, but at most three are in use at once: the
+        // background, the value being replaced and the new one.
+        for value in 1..100 {
+            container.set(0, value);
+            assert!(container.bits_per_entry() <= 2, "after {value}");
+        }
+        assert_eq!(*container.get(0), 99);
+    }
+
+    #[test]
+    fn iterator_matches_index() {
+        let mut container = PalettedContainer::new(50, 0u16);
+        for index in 0..50 {
+            container.set(index, (index % 7) as u16);
+        }
+
+        let expected = (0..50).map(|index| *container.get(index)).collect::<Vec<_>>();
+        assert_eq!(container.iter().copied().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            container.iter().rev().copied().collect::<Vec<_>>(),
+            expected.iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(container.iter().len(), 50);
+
+        // Both ends meet in the middle.
+        let mut iter = container.iter();
+        assert_eq!(iter.next(), Some(&0));
+        assert_eq!(iter.next_back(), Some(&0));
+        assert_eq!(iter.len(), 48);
+        assert_eq!(iter.count(), 48);
+    }
+}
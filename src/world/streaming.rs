@@ -0,0 +1,224 @@
+use std::{cmp::Reverse, collections::HashSet, iter};
+
+use bevy::{
+    math::{I64Vec2, I64Vec3},
+    prelude::*,
+};
+
+use super::{ChunkMap, Level, Load};
+
+/// Streams the chunks of every [`Level`] around the entity it is attached
+/// to, e.g. a player or camera.
+///
+/// Missing chunks are loaded nearest first, in a spiral around the loader,
+/// and chunks out of range of every loader are unloaded. Levels are left
+/// alone while no loader exists.
+#[derive(Component, Debug, Clone, Copy)]
+#[require(Transform)]
+pub struct ChunkLoader {
+    /// Horizontal distance in chunks to keep loaded.
+    pub render_distance: u32,
+    /// Vertical distance in chunks to keep loaded.
+    pub vertical_distance: u32,
+    /// Loaders with a higher priority get their chunks loaded first.
+    pub priority: i32,
+}
+
+impl Default for ChunkLoader {
+    fn default() -> Self {
+        Self {
+            // Stays within the renderer's `MAX_CHUNK_COUNT` chunks.
+            render_distance: 4,
+            vertical_distance: 1,
+            priority: 0,
+        }
+    }
+}
+
+impl ChunkLoader {
+    /// Whether `chunk` is in range of a loader in chunk `center`.
+    pub fn in_range(&self, center: I64Vec3, chunk: I64Vec3) -> bool {
+        let distance = (chunk - center).abs();
+        distance.x.max(distance.z) <= self.render_distance as i64
+            && distance.y <= self.vertical_distance as i64
+    }
+
+    /// Chunk offsets in range of the loader, nearest first. Horizontally
+    /// the columns spiral out ring by ring; within a column, chunks
+    /// alternate above and below the loader.
+    pub fn spiral(&self) -> impl Iterator<Item = I64Vec3> {
+        let vertical_distance = self.vertical_distance as i64;
+        spiral(self.render_distance as i64).flat_map(move |column| {
+            (0..=2 * vertical_distance).map(move |i| {
+                let y = if i % 2 == 1 { (i + 1) / 2 } else { -(i / 2) };
+                I64Vec3::new(column.x, y, column.y)
+            })
+        })
+    }
+}
+
+/// Horizontal offsets within `radius`, ring by ring, each ring walked
+/// around its perimeter.
+fn spiral(radius: i64) -> impl Iterator<Item = I64Vec2> {
+    iter::once(I64Vec2::ZERO).chain((1..=radius).flat_map(|ring| {
+        [
+            (I64Vec2::new(ring, -ring), I64Vec2::Y),
+            (I64Vec2::new(ring, ring), I64Vec2::NEG_X),
+            (I64Vec2::new(-ring, ring), I64Vec2::NEG_Y),
+            (I64Vec2::new(-ring, -ring), I64Vec2::X),
+        ]
+        .into_iter()
+        .flat_map(move |(start, step)| (0..2 * ring).map(move |i| start + step * i))
+    }))
+}
+
+/// How many chunks [`ChunkLoader`]s may load and unload per frame, for each
+/// chunk size, to avoid hitches when moving fast or teleporting.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct ChunkStreamingBudget {
+    pub loads_per_frame: usize,
+    pub unloads_per_frame: usize,
+}
+
+impl Default for ChunkStreamingBudget {
+    fn default() -> Self {
+        Self {
+            loads_per_frame: 8,
+            unloads_per_frame: 32,
+        }
+    }
+}
+
+/// Chunk containing `translation`, in the space of a level made of chunks
+/// of `SIZE`. Blocks are centered on integer positions.
+fn chunk_at<const SIZE: usize>(translation: Vec3) -> I64Vec3 {
+    ((translation + 0.5) / SIZE as f32).floor().as_i64vec3()
+}
+
+pub(crate) fn stream_chunks<const SIZE: usize>(
+    mut commands: Commands,
+    budget: Res<ChunkStreamingBudget>,
+    levels: Query<(Entity, &Level, &ChunkMap, &GlobalTransform)>,
+    loaders: Query<(&ChunkLoader, &GlobalTransform)>,
+) {
+    if loaders.is_empty() {
+        return;
+    }
+
+    let mut loaders = loaders.iter().collect::<Vec<_>>();
+    loaders.sort_by_key(|(loader, _)| Reverse(loader.priority));
+
+    for (entity, level, map, level_transform) in &levels {
+        if level.chunk_size != SIZE {
+            continue;
+        }
+
+        let level_from_world = level_transform.affine().inverse();
+        let centers = loaders
+            .iter()
+            .map(|(loader, transform)| {
+                let translation = level_from_world.transform_point3(transform.translation());
+                (*loader, chunk_at::<SIZE>(translation))
+            })
+            .collect::<Vec<_>>();
+
+        let mut queued = HashSet::new();
+        'load: for (loader, center) in &centers {
+            for offset in loader.spiral() {
+                if queued.len() >= budget.loads_per_frame {
+                    break 'load;
+                }
+                let chunk = center + offset;
+                if !map.contains(chunk) && queued.insert(chunk) {
+                    (entity, level).load::<SIZE>(chunk, &mut commands);
+                }
+            }
+        }
+
+        map.iter()
+            .filter(|(chunk, _)| {
+                !centers
+                    .iter()
+                    .any(|(loader, center)| loader.in_range(*center, *chunk))
+            })
+            .take(budget.unloads_per_frame)
+            .for_each(|(_, chunk)| commands.entity(chunk).despawn());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{world::generation::flat, VoxelPlugin};
+
+    #[test]
+    fn spiral_is_nearest_first() {
+        let loader = ChunkLoader {
+            render_distance: 2,
+            vertical_distance: 1,
+            priority: 0,
+        };
+        let offsets = loader.spiral().collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 5 * 5 * 3);
+        assert_eq!(offsets.iter().collect::<HashSet<_>>().len(), offsets.len());
+        assert_eq!(offsets[..3], [I64Vec3::ZERO, I64Vec3::Y, I64Vec3::NEG_Y]);
+
+        let ring = |offset: &I64Vec3| offset.x.abs().max(offset.z.abs());
+        assert!(offsets.windows(2).all(|pair| ring(&pair[0]) <= ring(&pair[1])));
+        assert!(offsets.iter().all(|offset| loader.in_range(I64Vec3::ZERO, *offset)));
+    }
+
+    #[test]
+    fn in_range() {
+        let loader = ChunkLoader {
+            render_distance: 2,
+            vertical_distance: 1,
+            priority: 0,
+        };
+        let center = I64Vec3::new(10, -3, 7);
+        assert!(loader.in_range(center, center));
+        assert!(loader.in_range(center, center + I64Vec3::new(-2, 1, 2)));
+        assert!(!loader.in_range(center, center + I64Vec3::new(3, 0, 0)));
+        assert!(!loader.in_range(center, center + I64Vec3::new(0, 0, -3)));
+        assert!(!loader.in_range(center, center + I64Vec3::new(0, 2, 0)));
+        assert!(!loader.in_range(center, center + I64Vec3::new(0, -2, 0)));
+    }
+
+    #[test]
+    fn loads_and_unloads_within_budget() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, VoxelPlugin::default()));
+        app.insert_resource(ChunkStreamingBudget {
+            loads_per_frame: 5,
+            unloads_per_frame: 3,
+        });
+        let level = app.world_mut().spawn(Level::new(flat::<0>)).id();
+        let loader = app
+            .world_mut()
+            .spawn(ChunkLoader {
+                render_distance: 1,
+                vertical_distance: 0,
+                priority: 0,
+            })
+            .id();
+        let loaded = |app: &App| app.world().get::<ChunkMap>(level).unwrap().len();
+
+        app.update();
+        assert_eq!(loaded(&app), 5);
+        app.update();
+        assert_eq!(loaded(&app), 9);
+        app.update();
+        assert_eq!(loaded(&app), 9);
+
+        // Far enough that no chunk is kept.
+        app.world_mut().get_mut::<Transform>(loader).unwrap().translation.x = 16.0 * 10.0;
+        // The global transform follows at the end of the frame.
+        app.update();
+        assert_eq!(loaded(&app), 9);
+        app.update();
+        let map = app.world().get::<ChunkMap>(level).unwrap();
+        let old = map.iter().filter(|(chunk, _)| chunk.x <= 1).count();
+        assert_eq!(old, 9 - 3);
+        assert_eq!(map.len(), 9 - 3 + 5);
+    }
+}
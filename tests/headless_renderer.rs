@@ -0,0 +1,57 @@
+//! The renderer plugin loads without a GPU, e.g. on CI or a server.
+
+use bevy::{
+    prelude::*,
+    render::{
+        settings::{RenderCreation, WgpuSettings},
+        RenderApp, RenderPlugin,
+    },
+};
+use vkxl::{
+    render::VoxelRendererPlugin,
+    world::{chunk::Chunk16, generation, streaming::ChunkLoader, Level},
+    VoxelPlugin,
+};
+
+#[test]
+fn loads_without_gpu() {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        TransformPlugin,
+        AssetPlugin::default(),
+        WindowPlugin {
+            primary_window: None,
+            ..default()
+        },
+        RenderPlugin {
+            render_creation: RenderCreation::Automatic(WgpuSettings {
+                backends: None,
+                ..default()
+            }),
+            ..default()
+        },
+        ImagePlugin::default(),
+        VoxelPlugin::default(),
+        VoxelRendererPlugin::default(),
+    ));
+    app.finish();
+    app.cleanup();
+    assert!(app.get_sub_app(RenderApp).is_none());
+
+    app.world_mut().spawn(Level::new(generation::flat::<0>));
+    app.world_mut().spawn(ChunkLoader {
+        render_distance: 1,
+        vertical_distance: 1,
+        priority: 0,
+    });
+    for _ in 0..100 {
+        app.update();
+        let loaded = app.world_mut().query::<&Chunk16>().iter(app.world()).count();
+        if loaded == 27 {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    panic!("chunks were not generated");
+}